@@ -1,3 +1,4 @@
+{# Reads files, so it needs the fs capability: runtpl run examples/files.tpl sourcesList=src --allow fs #}
 List of files in directories
 {{foreach path in sourcesList}}
  - {{path}}
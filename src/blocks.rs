@@ -0,0 +1,166 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+
+lazy_static! {
+    static ref RE_BLOCK_TAG: Regex = Regex::new(
+        r#"(?m)(^\s*)?\{\{\s*(foreach|endfor|if|elif|else|endif|macro|endmacro|switch|case|endswitch|with|endwith|unless|endunless|capture|endcapture|sep|endsep|script|endscript)\b\s*((?:[^}"]|"[^"]*"|\}[^}])*?)\s*\}\}"#
+    )
+    .unwrap();
+}
+
+/// A block tag such as `{{foreach ...}}`, `{{if ...}}`, `{{else}}` or `{{endfor}}`.
+/// When the tag begins a line, `start..end` also covers the indentation before
+/// it and the line break right after it, so block tags don't leave blank lines.
+#[derive(Debug, Clone, Copy)]
+pub struct BlockTag<'a> {
+    pub start: usize,
+    pub end: usize,
+    pub keyword: &'a str,
+    pub args: &'a str,
+}
+
+/// One section of a block: the tag opening it and the text up to the next tag
+/// of the same block. `{{if a}}x{{else}}y{{endif}}` has two sections.
+#[derive(Debug)]
+pub struct Section<'a> {
+    pub tag: BlockTag<'a>,
+    pub body: &'a str,
+}
+
+/// A complete block, from its opening tag to the matching closing tag.
+#[derive(Debug)]
+pub struct Block<'a> {
+    pub start: usize,
+    pub end: usize,
+    pub sections: Vec<Section<'a>>,
+}
+
+impl Block<'_> {
+    pub fn keyword(&self) -> &str {
+        self.sections[0].tag.keyword
+    }
+}
+
+fn closing_keyword(keyword: &str) -> Option<&'static str> {
+    match keyword {
+        "foreach" => Some("endfor"),
+        "if" => Some("endif"),
+        "macro" => Some("endmacro"),
+        "switch" => Some("endswitch"),
+        "with" => Some("endwith"),
+        "unless" => Some("endunless"),
+        "capture" => Some("endcapture"),
+        "sep" => Some("endsep"),
+        "script" => Some("endscript"),
+        _ => None,
+    }
+}
+
+fn is_closing(keyword: &str) -> bool {
+    keyword.starts_with("end")
+}
+
+/// Whether a tag spelled like a block keyword is really a variable of that
+/// name. `with` and `capture` always take an argument, and `{{sep}}` and
+/// `{{script}}` open a block only when written without spaces, so
+/// `{{ with }}` or `{{ script }}` still print fields. A pipe right after the
+/// keyword means a filtered variable.
+fn is_variable(keyword: &str, args: &str, tag: &str) -> bool {
+    if args.starts_with('|') {
+        return true;
+    }
+    match keyword {
+        "with" | "capture" => args.is_empty(),
+        "sep" | "script" => args.is_empty() && tag != format!("{{{{{}}}}}", keyword),
+        _ => false,
+    }
+}
+
+/// Returns all block tags of the template in order of appearance.
+pub fn scan(template: &str) -> Vec<BlockTag<'_>> {
+    RE_BLOCK_TAG
+        .captures_iter(template)
+        .filter(|caps| {
+            let indent = caps.get(1).map_or(0, |m| m.len());
+            let tag = &caps[0][indent..];
+            !is_variable(&caps[2], &caps[3], tag)
+        })
+        .map(|caps| {
+            let whole = caps.get(0).unwrap();
+            let mut end = whole.end();
+            if caps.get(1).is_some() {
+                let rest = &template[end..];
+                if rest.starts_with("\r\n") {
+                    end += 2;
+                } else if rest.starts_with('\n') {
+                    end += 1;
+                }
+            }
+            BlockTag {
+                start: whole.start(),
+                end,
+                keyword: caps.get(2).unwrap().as_str(),
+                args: caps.get(3).unwrap().as_str(),
+            }
+        })
+        .collect()
+}
+
+/// Finds the first top-level block of the template and splits it into sections.
+/// Middle tags (`{{elif}}`, `{{else}}`) of nested blocks are left inside the
+/// section bodies, so each level only sees its own structure.
+pub fn find_first_block(template: &str) -> Result<Option<Block<'_>>, String> {
+    let tags = scan(template);
+    let Some(open_idx) = tags
+        .iter()
+        .position(|t| closing_keyword(t.keyword).is_some())
+    else {
+        return Ok(None);
+    };
+
+    let open = tags[open_idx];
+    let expected_close = closing_keyword(open.keyword).unwrap();
+    let mut dividers = Vec::new();
+    let mut depth = 0;
+
+    for tag in &tags[open_idx + 1..] {
+        if closing_keyword(tag.keyword).is_some() {
+            depth += 1;
+        } else if is_closing(tag.keyword) {
+            if depth > 0 {
+                depth -= 1;
+                continue;
+            }
+            if tag.keyword != expected_close {
+                return Err(format!(
+                    "Unexpected '{{{{{}}}}}' inside '{{{{{} {}}}}}', expected '{{{{{}}}}}'",
+                    tag.keyword, open.keyword, open.args, expected_close
+                ));
+            }
+
+            let mut heads = vec![open];
+            heads.extend(dividers.iter().copied());
+            let mut sections = Vec::new();
+            for (i, head) in heads.iter().enumerate() {
+                let body_end = heads.get(i + 1).map_or(tag.start, |next| next.start);
+                sections.push(Section {
+                    tag: *head,
+                    body: &template[head.end..body_end],
+                });
+            }
+
+            return Ok(Some(Block {
+                start: open.start,
+                end: tag.end,
+                sections,
+            }));
+        } else if depth == 0 {
+            dividers.push(*tag);
+        }
+    }
+
+    Err(format!(
+        "Missing '{{{{{}}}}}' for '{{{{{} {}}}}}'",
+        expected_close, open.keyword, open.args
+    ))
+}
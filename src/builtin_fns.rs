@@ -1,6 +1,16 @@
+use crate::filters;
+use crate::warnings;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat, Utc};
+use ignore::WalkBuilder;
 use serde_json::{Map, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
-use walkdir::WalkDir;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 
 /// Обрабатывает ошибку, возвращая её в виде `Err(Value::String(...))`
 macro_rules! func_err {
@@ -9,9 +19,160 @@ macro_rules! func_err {
     };
 }
 
-/// Встроенная функция `files(source, recursive, exclude_names, exclude_paths)`
-/// Возвращает массив объектов, где каждый объект {name, path, absolute_path, content}.
+/// Читает содержимое файла целиком или только первые/последние N строк.
+/// Возвращает байты содержимого, флаг, был ли файл обрезан, и номер первой прочитанной строки.
+fn read_content(
+    path: &Path,
+    head_lines: Option<usize>,
+    tail_lines: Option<usize>,
+) -> io::Result<(Vec<u8>, bool, usize)> {
+    if head_lines.is_none() && tail_lines.is_none() {
+        return Ok((fs::read(path)?, false, 1));
+    }
+
+    let mut reader = BufReader::new(fs::File::open(path)?);
+    let mut kept = VecDeque::new();
+    let mut truncated = false;
+    let mut first_line = 1;
+    let mut line = Vec::new();
+
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        if let Some(n) = head_lines {
+            if kept.len() == n {
+                truncated = true;
+                break;
+            }
+        } else if let Some(n) = tail_lines
+            && kept.len() == n
+        {
+            kept.pop_front();
+            first_line += 1;
+            truncated = true;
+        }
+        kept.push_back(std::mem::take(&mut line));
+    }
+
+    Ok((kept.into_iter().flatten().collect(), truncated, first_line))
+}
+
+/// Добавляет к каждой строке префикс "N | " с её номером в файле,
+/// выравнивая номера по ширине последнего.
+fn number_content_lines(content: &str, first_line: usize) -> String {
+    let lines: Vec<&str> = content.split_inclusive('\n').collect();
+    let width = (first_line + lines.len().saturating_sub(1))
+        .to_string()
+        .len();
+    lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| format!("{:>width$} | {}", first_line + i, line, width = width))
+        .collect()
+}
+
+/// Больше потоков не ускоряет чтение даже с сетевых дисков
+const MAX_READ_THREADS: usize = 8;
+
+/// Сколько байт из начала файла проверяется на нулевые байты
+const BINARY_SNIFF_LEN: u64 = 8000;
+
+/// Считает файл бинарным, если в его начале есть нулевой байт (как это делает git).
+fn looks_binary(path: &Path) -> io::Result<bool> {
+    let mut head = Vec::new();
+    fs::File::open(path)?
+        .take(BINARY_SNIFF_LEN)
+        .read_to_end(&mut head)?;
+    Ok(head.contains(&0))
+}
+
+/// Идентификатор языка для блоков кода Markdown по расширению файла
+fn language_for_extension(extension: &str) -> Option<&'static str> {
+    let language = match extension.to_ascii_lowercase().as_str() {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" => "typescript",
+        "tsx" => "tsx",
+        "jsx" => "jsx",
+        "go" => "go",
+        "java" => "java",
+        "kt" | "kts" => "kotlin",
+        "c" | "h" => "c",
+        "cpp" | "cc" | "cxx" | "hpp" | "hh" => "cpp",
+        "cs" => "csharp",
+        "rb" => "ruby",
+        "php" => "php",
+        "swift" => "swift",
+        "sh" | "bash" | "zsh" => "bash",
+        "ps1" => "powershell",
+        "sql" => "sql",
+        "html" | "htm" => "html",
+        "css" => "css",
+        "scss" => "scss",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "xml" => "xml",
+        "md" | "markdown" => "markdown",
+        "lua" => "lua",
+        "dockerfile" => "dockerfile",
+        _ => return None,
+    };
+    Some(language)
+}
+
+/// Дополнительные поля {size, modified, extension, line_count, language} для `metadata: true`.
+/// Без прочитанного содержимого `line_count` - null.
+fn insert_file_metadata(file_obj: &mut Map<String, Value>, path: &Path, content: Option<&str>) {
+    let metadata = fs::metadata(path).ok();
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string());
+    let modified = metadata
+        .as_ref()
+        .and_then(|m| m.modified().ok())
+        .map(|time| DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Secs, true));
+
+    file_obj.insert(
+        "size".to_string(),
+        metadata.map_or(Value::Null, |m| Value::from(m.len())),
+    );
+    file_obj.insert("modified".to_string(), optional_string(modified));
+    file_obj.insert(
+        "line_count".to_string(),
+        content.map_or(Value::Null, |content| Value::from(content.lines().count())),
+    );
+    file_obj.insert(
+        "language".to_string(),
+        optional_string(
+            extension
+                .as_deref()
+                .and_then(language_for_extension)
+                .map(str::to_string),
+        ),
+    );
+    file_obj.insert("extension".to_string(), optional_string(extension));
+}
+
+fn optional_count(args: &Map<String, Value>, name: &str) -> Result<Option<usize>, Value> {
+    match args.get(name) {
+        None => Ok(None),
+        Some(Value::Number(n)) if n.as_u64().is_some() => Ok(n.as_u64().map(|n| n as usize)),
+        _ => func_err!("'{}' argument must be a non-negative integer", name),
+    }
+}
+
+/// Встроенная функция `files(source, recursive, max_depth, follow_symlinks, include, exclude_names, exclude_paths, content, head_lines, tail_lines, number_lines, max_chars, encoding, use_gitignore, max_size_kb, metadata, sort)`
+/// Возвращает массив объектов, где каждый объект {name, path, absolute_path, content, truncated}.
 /// 'path' - относительный путь (к текущей рабочей директории), 'absolute_path' - канонический абсолютный путь.
+/// При `use_gitignore` (по умолчанию true) пропускаются файлы из .gitignore и каталог .git.
+/// Бинарные файлы и файлы больше `max_size_kb` не читаются.
+/// С `metadata: true` объекты дополняются полями {size, modified, extension, line_count, language}.
+/// Результат отсортирован по `sort` (по умолчанию по пути).
+/// `max_chars` обрезает содержимое до N символов (после `head_lines`/`tail_lines`).
+/// `max_depth` (1 - только файлы самого источника) имеет приоритет над `recursive`.
+/// С `content: false` файлы не читаются, в объектах нет `content` и `truncated`.
+/// С `number_lines: true` строки содержимого начинаются с "N | " (номер строки в файле).
+/// Файлы не в UTF-8 по умолчанию читаются с заменой байтов (`encoding: "lossy"`).
 pub fn files(args: &Map<String, Value>) -> Result<Value, Value> {
     let source_paths: Vec<String> = match args.get("source") {
         Some(Value::Array(arr)) => arr
@@ -52,25 +213,111 @@ pub fn files(args: &Map<String, Value>) -> Result<Value, Value> {
         _ => func_err!("'exclude_paths' argument must be an array of strings"),
     };
 
-    let mut result_files = Vec::new();
+    let include_patterns: Vec<glob::Pattern> = match args.get("include") {
+        Some(Value::Array(arr)) => arr
+            .iter()
+            .filter_map(|v| v.as_str())
+            .map(glob::Pattern::new)
+            .collect::<Result<_, _>>()
+            .map_err(|e| Value::String(format!("Invalid 'include' pattern: {}", e)))?,
+        Some(Value::String(pattern)) => match glob::Pattern::new(pattern) {
+            Ok(pattern) => vec![pattern],
+            Err(e) => func_err!("Invalid 'include' pattern '{}': {}", pattern, e),
+        },
+        None => vec![],
+        _ => func_err!("'include' argument must be a glob pattern or an array of them"),
+    };
+
+    let use_gitignore = match args.get("use_gitignore") {
+        Some(Value::Bool(b)) => *b,
+        None => true,
+        _ => func_err!("'use_gitignore' argument must be a boolean (true or false)"),
+    };
+
+    let head_lines = optional_count(args, "head_lines")?;
+    let tail_lines = optional_count(args, "tail_lines")?;
+    if head_lines.is_some() && tail_lines.is_some() {
+        func_err!("'head_lines' and 'tail_lines' cannot be used together");
+    }
+    let sort = match args.get("sort") {
+        Some(Value::String(order))
+            if ["path", "name", "size", "modified"].contains(&order.as_str()) =>
+        {
+            order.as_str()
+        }
+        None => "path",
+        _ => {
+            func_err!("'sort' argument must be one of \"path\", \"name\", \"size\" or \"modified\"")
+        }
+    };
+    let with_metadata = match args.get("metadata") {
+        Some(Value::Bool(b)) => *b,
+        None => false,
+        _ => func_err!("'metadata' argument must be a boolean (true or false)"),
+    };
+    let with_content = match args.get("content") {
+        Some(Value::Bool(b)) => *b,
+        None => true,
+        _ => func_err!("'content' argument must be a boolean (true or false)"),
+    };
+    let max_chars = optional_count(args, "max_chars")?;
+    let encoding = match args.get("encoding").map(Value::as_str) {
+        None | Some(Some("lossy")) => Encoding::Lossy,
+        Some(Some("skip")) => Encoding::Skip,
+        Some(Some("strict")) => Encoding::Strict,
+        _ => func_err!("'encoding' argument must be one of \"lossy\", \"skip\" or \"strict\""),
+    };
+    let number_lines = match args.get("number_lines") {
+        Some(Value::Bool(b)) => *b,
+        None => false,
+        _ => func_err!("'number_lines' argument must be a boolean (true or false)"),
+    };
+    let max_depth = optional_count(args, "max_depth")?;
+    let follow_symlinks = match args.get("follow_symlinks") {
+        Some(Value::Bool(b)) => *b,
+        None => false,
+        _ => func_err!("'follow_symlinks' argument must be a boolean (true or false)"),
+    };
+    let max_size = optional_count(args, "max_size_kb")?.map(|kb| kb as u64 * 1024);
+
+    // Сначала обходятся каталоги, затем найденные файлы читаются параллельно
+    let mut candidates: Vec<(PathBuf, PathBuf)> = Vec::new();
+    let mut seen_paths = HashSet::new();
 
     for path in source_paths {
-        let mut walker_builder = WalkDir::new(&path);
-        if !recursive {
-            walker_builder = walker_builder.max_depth(1);
+        let mut walker_builder = WalkBuilder::new(&path);
+        walker_builder
+            .standard_filters(false)
+            .parents(use_gitignore)
+            .git_ignore(use_gitignore)
+            .git_global(use_gitignore)
+            .git_exclude(use_gitignore)
+            .require_git(false);
+        if use_gitignore {
+            walker_builder.filter_entry(|entry| entry.file_name() != ".git");
+        }
+        walker_builder.follow_links(follow_symlinks);
+        if max_depth.is_some() {
+            walker_builder.max_depth(max_depth);
+        } else if !recursive {
+            walker_builder.max_depth(Some(1));
         }
-        let walker = walker_builder.into_iter();
+        let walker = walker_builder.build();
 
         for entry_result in walker {
             let entry = match entry_result {
                 Ok(e) => e,
                 Err(e) => {
-                    eprintln!("Warning: Skipping path due to error: {}", e);
+                    warnings::warn(format!("Skipping path due to error: {}", e));
                     continue;
                 }
             };
 
-            if entry.file_type().is_dir() {
+            if entry.file_type().is_none_or(|file_type| file_type.is_dir()) {
+                continue;
+            }
+            // Ссылка на каталог без follow_symlinks
+            if entry.path_is_symlink() && entry.path().is_dir() {
                 continue;
             }
 
@@ -85,43 +332,1425 @@ pub fn files(args: &Map<String, Value>) -> Result<Value, Value> {
             if exclude_paths.iter().any(|p| relative_path_str.contains(p)) {
                 continue;
             }
+            // Шаблоны `include` сравниваются с путём относительно источника
+            if !include_patterns.is_empty() {
+                let source_relative = file_path
+                    .strip_prefix(&path)
+                    .ok()
+                    .filter(|rel| !rel.as_os_str().is_empty())
+                    .unwrap_or_else(|| Path::new(file_path.file_name().unwrap_or_default()));
+                if !include_patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path(source_relative))
+                {
+                    continue;
+                }
+            }
 
             let absolute_path = match fs::canonicalize(file_path) {
                 Ok(path) => path,
                 Err(e) => {
-                    eprintln!(
-                        "Warning: Skipping file '{}' because its absolute path could not be determined: {}",
+                    warnings::warn(format!(
+                        "Skipping file '{}' because its absolute path could not be determined: {}",
                         file_path.display(),
                         e
-                    );
+                    ));
                     continue;
                 }
             };
-            let absolute_path_str = absolute_path.to_string_lossy();
-
-            match fs::read_to_string(file_path) {
-                Ok(content) => {
-                    let mut file_obj = Map::new();
-                    file_obj.insert("name".to_string(), Value::String(file_name_str.to_string()));
-
-                    file_obj.insert(
-                        "path".to_string(),
-                        Value::String(relative_path_str.to_string()),
-                    );
-
-                    file_obj.insert(
-                        "absolute_path".to_string(),
-                        Value::String(absolute_path_str.to_string()),
-                    );
-                    file_obj.insert("content".to_string(), Value::String(content));
-                    result_files.push(Value::Object(file_obj));
-                }
-                Err(e) => {
-                    eprintln!("Warning: Could not read file {}: {}", relative_path_str, e);
-                }
+            // Пересекающиеся источники (например, "./src" и "./src/util") не должны давать дубликаты
+            if !seen_paths.insert(absolute_path.clone()) {
+                continue;
             }
+
+            if let Some(max_size) = max_size
+                && let Ok(metadata) = entry.metadata()
+                && metadata.len() > max_size
+            {
+                warnings::warn(format!(
+                    "Skipping file {} because it is larger than max_size_kb ({} KB)",
+                    relative_path_str,
+                    metadata.len().div_ceil(1024)
+                ));
+                continue;
+            }
+            candidates.push((file_path.to_path_buf(), absolute_path));
+        }
+    }
+
+    let read_options = ReadOptions {
+        head_lines,
+        tail_lines,
+        max_chars,
+        number_lines,
+        with_metadata,
+        with_content,
+        encoding,
+    };
+    let mut result_files = Vec::with_capacity(candidates.len());
+    for result in read_files_parallel(&candidates, &read_options) {
+        match result {
+            Ok(Some(file_obj)) => result_files.push(file_obj),
+            Ok(None) => {}
+            Err(ReadIssue::Warning(warning)) => warnings::warn(warning),
+            Err(ReadIssue::Fatal(error)) => func_err!("{}", error),
         }
     }
 
+    sort_files(&mut result_files, sort);
     Ok(Value::Array(result_files))
 }
+
+/// Что и как читать из каждого файла в `files`
+struct ReadOptions {
+    head_lines: Option<usize>,
+    tail_lines: Option<usize>,
+    max_chars: Option<usize>,
+    number_lines: bool,
+    with_metadata: bool,
+    with_content: bool,
+    encoding: Encoding,
+}
+
+/// Как поступать с файлами, которые не являются корректным UTF-8
+#[derive(Clone, Copy)]
+enum Encoding {
+    /// Заменять некорректные байты на U+FFFD
+    Lossy,
+    /// Пропускать файл с предупреждением
+    Skip,
+    /// Прерывать `files` с ошибкой
+    Strict,
+}
+
+/// Проблема с одним файлом: предупреждение или ошибка всего вызова `files`
+enum ReadIssue {
+    Warning(String),
+    Fatal(String),
+}
+
+/// Читает файл и строит объект {name, path, absolute_path, content, truncated}.
+/// `Ok(None)` - бинарный файл, который пропускается молча; `Err` - текст предупреждения.
+fn read_file_object(
+    file_path: &Path,
+    absolute_path: &Path,
+    options: &ReadOptions,
+) -> Result<Option<Value>, ReadIssue> {
+    let relative_path_str = file_path.to_string_lossy();
+    // Бинарные файлы (картинки, архивы) пропускаются молча
+    if looks_binary(file_path).unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let mut file_obj = Map::new();
+    let file_name_str = file_path.file_name().unwrap_or_default().to_string_lossy();
+    file_obj.insert("name".to_string(), Value::String(file_name_str.to_string()));
+    file_obj.insert(
+        "path".to_string(),
+        Value::String(relative_path_str.to_string()),
+    );
+    file_obj.insert(
+        "absolute_path".to_string(),
+        Value::String(absolute_path.to_string_lossy().to_string()),
+    );
+    if !options.with_content {
+        if options.with_metadata {
+            insert_file_metadata(&mut file_obj, file_path, None);
+        }
+        return Ok(Some(Value::Object(file_obj)));
+    }
+
+    let (bytes, mut truncated, first_line) =
+        read_content(file_path, options.head_lines, options.tail_lines).map_err(|e| {
+            ReadIssue::Warning(format!("Could not read file {}: {}", relative_path_str, e))
+        })?;
+    let mut content = match String::from_utf8(bytes) {
+        Ok(content) => content,
+        Err(e) => match options.encoding {
+            Encoding::Lossy => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+            Encoding::Skip => {
+                return Err(ReadIssue::Warning(format!(
+                    "Skipping file {} because it is not valid UTF-8",
+                    relative_path_str
+                )));
+            }
+            Encoding::Strict => {
+                return Err(ReadIssue::Fatal(format!(
+                    "File {} is not valid UTF-8",
+                    relative_path_str
+                )));
+            }
+        },
+    };
+    if options.number_lines {
+        content = number_content_lines(&content, first_line);
+    }
+    if let Some(max_chars) = options.max_chars
+        && let Some((cut, _)) = content.char_indices().nth(max_chars)
+    {
+        content.truncate(cut);
+        truncated = true;
+    }
+
+    if options.with_metadata {
+        insert_file_metadata(&mut file_obj, file_path, Some(&content));
+    }
+    file_obj.insert("content".to_string(), Value::String(content));
+    file_obj.insert("truncated".to_string(), Value::Bool(truncated));
+    Ok(Some(Value::Object(file_obj)))
+}
+
+/// Читает файлы в нескольких потоках, возвращая результаты в исходном порядке.
+/// Предупреждения возвращаются, а не выводятся: `warnings` собирает их по потокам.
+fn read_files_parallel(
+    candidates: &[(PathBuf, PathBuf)],
+    options: &ReadOptions,
+) -> Vec<Result<Option<Value>, ReadIssue>> {
+    let threads = std::thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(MAX_READ_THREADS);
+    let chunk_size = candidates.len().div_ceil(threads).max(1);
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = candidates
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|(file_path, absolute_path)| {
+                            read_file_object(file_path, absolute_path, options)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("file reader thread panicked"))
+            .collect()
+    })
+}
+
+/// Сортирует объекты файлов по `order` ("path", "name", "size" или "modified").
+/// При равенстве ключа порядок определяет путь, чтобы результат был одинаковым на разных машинах.
+fn sort_files(result_files: &mut [Value], order: &str) {
+    let field = |file: &Value, key: &str| file[key].as_str().unwrap_or_default().to_string();
+    match order {
+        "name" => result_files.sort_by_cached_key(|f| (field(f, "name"), field(f, "path"))),
+        "size" => result_files.sort_by_cached_key(|f| {
+            let size = fs::metadata(field(f, "absolute_path")).map_or(0, |m| m.len());
+            (size, field(f, "path"))
+        }),
+        "modified" => result_files.sort_by_cached_key(|f| {
+            let modified = fs::metadata(field(f, "absolute_path"))
+                .and_then(|m| m.modified())
+                .ok();
+            (modified, field(f, "path"))
+        }),
+        _ => result_files.sort_by_cached_key(|f| field(f, "path")),
+    }
+}
+
+fn read_env_var(name: &str, default: Option<&Value>) -> Result<Value, Value> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Value::String(value)),
+        Err(_) => match default {
+            Some(default) => Ok(default.clone()),
+            None => func_err!("Environment variable '{}' is not set", name),
+        },
+    }
+}
+
+/// Встроенная функция `env(name, names, default)`
+/// С `name` возвращает значение одной переменной окружения строкой,
+/// с `names` - массив объектов {name, value}. Неустановленная переменная - ошибка,
+/// если не передан `default`.
+pub fn env(args: &Map<String, Value>) -> Result<Value, Value> {
+    let default = args.get("default");
+    match (args.get("name"), args.get("names")) {
+        (Some(Value::String(name)), None) => read_env_var(name, default),
+        (None, Some(Value::Array(names))) => names
+            .iter()
+            .map(|name| {
+                let Some(name) = name.as_str() else {
+                    func_err!("'names' argument must be an array of strings");
+                };
+                let mut var_obj = Map::new();
+                var_obj.insert("name".to_string(), Value::String(name.to_string()));
+                var_obj.insert("value".to_string(), read_env_var(name, default)?);
+                Ok(Value::Object(var_obj))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array),
+        _ => func_err!(
+            "'env' function requires either a 'name' string (e.g., name: \"HOME\") or a 'names' array (e.g., names: [\"USER\", \"SHELL\"])"
+        ),
+    }
+}
+
+/// Запускает `git` в каталоге `dir`. Возвращает вывод без завершающего перевода строки
+/// или `None`, если команда завершилась с ошибкой (например, нет такого remote).
+fn run_git(dir: &str, git_args: &[&str]) -> Result<Option<String>, Value> {
+    let output = match Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(git_args)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => func_err!("Could not run git: {}", e),
+    };
+    if !output.status.success() {
+        return Ok(None);
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(Some(stdout.trim_end_matches(['\r', '\n']).to_string()))
+}
+
+fn ensure_git_repo(dir: &str) -> Result<(), Value> {
+    if run_git(dir, &["rev-parse", "--git-dir"])?.is_none() {
+        func_err!("'{}' is not inside a git repository", dir);
+    }
+    Ok(())
+}
+
+fn optional_string(value: Option<String>) -> Value {
+    value.map(Value::String).unwrap_or(Value::Null)
+}
+
+/// Встроенная функция `git(path, remote)`
+/// Возвращает объект {branch, commit: {hash, short_hash, message, author, email, date}, dirty, remote_url}
+/// для репозитория в `path` (по умолчанию текущий каталог). `branch` - null при detached HEAD,
+/// `commit` - null в репозитории без коммитов, `remote_url` - null, если remote (по умолчанию "origin") не настроен.
+pub fn git(args: &Map<String, Value>) -> Result<Value, Value> {
+    let dir = match args.get("path") {
+        Some(Value::String(path)) => path.as_str(),
+        None => ".",
+        _ => func_err!("'path' argument must be a string"),
+    };
+    let remote = match args.get("remote") {
+        Some(Value::String(remote)) => remote.as_str(),
+        None => "origin",
+        _ => func_err!("'remote' argument must be a string"),
+    };
+
+    ensure_git_repo(dir)?;
+
+    let branch = run_git(dir, &["symbolic-ref", "--short", "-q", "HEAD"])?;
+
+    // Поля коммита разделены символом \x1f, который не встречается в сообщениях
+    let commit = run_git(
+        dir,
+        &["log", "-1", "--format=%H%x1f%h%x1f%s%x1f%an%x1f%ae%x1f%aI"],
+    )?
+    .filter(|line| !line.is_empty())
+    .map(|line| {
+        let fields: Vec<&str> = line.split('\x1f').collect();
+        let mut commit_obj = Map::new();
+        for (key, value) in ["hash", "short_hash", "message", "author", "email", "date"]
+            .iter()
+            .zip(fields)
+        {
+            commit_obj.insert(key.to_string(), Value::String(value.to_string()));
+        }
+        Value::Object(commit_obj)
+    });
+
+    let dirty = run_git(dir, &["status", "--porcelain"])?.is_some_and(|status| !status.is_empty());
+    let remote_url = run_git(dir, &["remote", "get-url", remote])?;
+
+    let mut git_obj = Map::new();
+    git_obj.insert("branch".to_string(), optional_string(branch));
+    git_obj.insert("commit".to_string(), commit.unwrap_or(Value::Null));
+    git_obj.insert("dirty".to_string(), Value::Bool(dirty));
+    git_obj.insert("remote_url".to_string(), optional_string(remote_url));
+    Ok(Value::Object(git_obj))
+}
+
+fn diff_hunk(file: &str, hunk_header: Option<&str>, patch: &[&str]) -> Value {
+    let mut hunk_obj = Map::new();
+    hunk_obj.insert("file".to_string(), Value::String(file.to_string()));
+    hunk_obj.insert(
+        "hunk_header".to_string(),
+        optional_string(hunk_header.map(str::to_string)),
+    );
+    hunk_obj.insert("patch".to_string(), Value::String(patch.join("\n")));
+    Value::Object(hunk_obj)
+}
+
+/// Заголовок hunk'а и его строки
+type DiffHunk<'a> = (&'a str, Vec<&'a str>);
+
+/// Разбирает вывод `git diff` на массив объектов {file, hunk_header, patch}, по одному на hunk.
+/// Файлы без hunk'ов (бинарные, переименования, смена прав) дают один объект с `hunk_header: null`.
+fn parse_diff(diff: &str) -> Vec<Value> {
+    let mut files: Vec<(String, Vec<DiffHunk>)> = Vec::new();
+    let mut in_file_header = false;
+
+    for line in diff.lines() {
+        if let Some(paths) = line.strip_prefix("diff --git ") {
+            // Имя из "a/x b/y" уточняется строками "---"/"+++", если они есть
+            let file = paths
+                .rsplit_once(" b/")
+                .map_or(paths, |(_, new_path)| new_path);
+            files.push((file.to_string(), Vec::new()));
+            in_file_header = true;
+            continue;
+        }
+        let Some((file, hunks)) = files.last_mut() else {
+            continue;
+        };
+        if line.starts_with("@@") {
+            hunks.push((line, Vec::new()));
+            in_file_header = false;
+        } else if in_file_header {
+            if let Some(path) = line
+                .strip_prefix("+++ b/")
+                .or_else(|| line.strip_prefix("--- a/"))
+            {
+                // Имена с пробелами git завершает табуляцией
+                *file = path.trim_end_matches('\t').to_string();
+            }
+        } else if let Some((_, patch)) = hunks.last_mut() {
+            patch.push(line);
+        }
+    }
+
+    files
+        .iter()
+        .flat_map(|(file, hunks)| {
+            if hunks.is_empty() {
+                vec![diff_hunk(file, None, &[])]
+            } else {
+                hunks
+                    .iter()
+                    .map(|(header, patch)| diff_hunk(file, Some(header), patch))
+                    .collect()
+            }
+        })
+        .collect()
+}
+
+/// Встроенная функция `git_diff(staged, paths)`
+/// Возвращает изменения рабочего дерева (или индекса при `staged: true`) в виде массива
+/// объектов {file, hunk_header, patch}. `paths` ограничивает diff указанными путями.
+pub fn git_diff(args: &Map<String, Value>) -> Result<Value, Value> {
+    let staged = match args.get("staged") {
+        Some(Value::Bool(b)) => *b,
+        None => false,
+        _ => func_err!("'staged' argument must be a boolean (true or false)"),
+    };
+    let paths: Vec<&str> = match args.get("paths") {
+        Some(Value::Array(arr)) => arr
+            .iter()
+            .map(|v| v.as_str())
+            .collect::<Option<_>>()
+            .ok_or_else(|| Value::String("'paths' argument must be an array of strings".into()))?,
+        Some(Value::String(path)) => vec![path.as_str()],
+        None => Vec::new(),
+        _ => func_err!("'paths' argument must be an array of strings"),
+    };
+
+    ensure_git_repo(".")?;
+
+    let mut git_args = vec![
+        "-c",
+        "core.quotePath=false",
+        "diff",
+        "--no-color",
+        "--no-ext-diff",
+    ];
+    if staged {
+        git_args.push("--cached");
+    }
+    git_args.push("--");
+    git_args.extend(paths);
+
+    match run_git(".", &git_args)? {
+        Some(diff) => Ok(Value::Array(parse_diff(&diff))),
+        None => func_err!("'git diff' failed, check the 'paths' argument"),
+    }
+}
+
+/// Встроенная функция `exec(cmd)`
+/// Выполняет `cmd` через системную оболочку и возвращает объект {stdout, stderr, exit_code}.
+/// Ненулевой код возврата не считается ошибкой; `exit_code` - null, если процесс убит сигналом.
+/// Доступна только с правом `exec` (`--allow exec`), проверка выполняется в `Engine`.
+pub fn exec(args: &Map<String, Value>) -> Result<Value, Value> {
+    let cmd = match args.get("cmd") {
+        Some(Value::String(cmd)) => cmd,
+        _ => func_err!("'exec' function requires a 'cmd' string argument (e.g., cmd: \"ls\")"),
+    };
+
+    let output = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(cmd).output()
+    } else {
+        Command::new("sh").arg("-c").arg(cmd).output()
+    };
+    let output = match output {
+        Ok(output) => output,
+        Err(e) => func_err!("Could not run '{}': {}", cmd, e),
+    };
+
+    let text = |bytes: &[u8]| {
+        let text = String::from_utf8_lossy(bytes);
+        Value::String(text.trim_end_matches(['\r', '\n']).to_string())
+    };
+    let mut result_obj = Map::new();
+    result_obj.insert("stdout".to_string(), text(&output.stdout));
+    result_obj.insert("stderr".to_string(), text(&output.stderr));
+    result_obj.insert(
+        "exit_code".to_string(),
+        output.status.code().map_or(Value::Null, Value::from),
+    );
+    Ok(Value::Object(result_obj))
+}
+
+/// Запускает `cmd` через shell, передаёт ему `input` на stdin и ждёт завершения
+pub(crate) fn run_shell_with_input(cmd: &str, input: &[u8]) -> io::Result<Output> {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(cmd);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(cmd);
+        command
+    };
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Команда может не читать stdin вовсе, поэтому ошибку записи (закрытый канал) не считаем фатальной
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(input);
+    }
+    child.wait_with_output()
+}
+
+/// Запускает внешнюю команду, зарегистрированную как функция шаблона в конфиге:
+/// аргументы вызова передаются ей JSON-объектом на stdin, её stdout разбирается как JSON.
+/// Ненулевой код выхода - ошибка с текстом из stderr.
+pub fn run_external_function(cmd: &str, args: &Map<String, Value>) -> Result<Value, Value> {
+    let input = Value::Object(args.clone()).to_string();
+    let output = match run_shell_with_input(cmd, input.as_bytes()) {
+        Ok(output) => output,
+        Err(e) => func_err!("Could not run '{}': {}", cmd, e),
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        func_err!(
+            "Command '{}' failed ({}): {}",
+            cmd,
+            output.status,
+            stderr.trim_end()
+        );
+    }
+    match serde_json::from_slice(&output.stdout) {
+        Ok(value) => Ok(value),
+        Err(e) => func_err!("Command '{}' did not print valid JSON: {}", cmd, e),
+    }
+}
+
+/// Подставляет в значение заголовка переменные окружения вида `${NAME}`,
+/// чтобы токены не приходилось хранить в шаблоне.
+fn expand_env_vars(value: &str) -> Result<String, Value> {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        result.push_str(&rest[..start]);
+        match read_env_var(name, None)? {
+            Value::String(var) => result.push_str(&var),
+            other => result.push_str(&other.to_string()),
+        }
+        rest = &rest[start + 3 + len..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Встроенная функция `fetch(url, method, headers, body, token_env, pointer, timeout)`
+/// Выполняет HTTP-запрос (по умолчанию GET). Ответ с Content-Type, содержащим "json", возвращается
+/// разобранным, остальные - строкой; `pointer` выбирает часть JSON-ответа. `body`-объект
+/// отправляется как JSON. `token_env` - имя переменной окружения с Bearer-токеном,
+/// `${NAME}` в заголовках заменяется значением переменной окружения.
+/// `timeout` задаётся в секундах (по умолчанию 30). Статус не 2xx - ошибка.
+pub fn fetch(args: &Map<String, Value>) -> Result<Value, Value> {
+    let url = match args.get("url") {
+        Some(Value::String(url)) => url,
+        _ => func_err!(
+            "'fetch' function requires a 'url' string argument (e.g., url: \"https://...\")"
+        ),
+    };
+    let method = match args.get("method") {
+        Some(Value::String(method)) => method.to_ascii_uppercase(),
+        None => "GET".to_string(),
+        _ => func_err!("'method' argument must be a string such as \"POST\""),
+    };
+    let timeout = match args.get("timeout") {
+        Some(Value::Number(n)) => match n.as_f64() {
+            Some(secs) if secs > 0.0 => Duration::from_secs_f64(secs),
+            _ => func_err!("'timeout' argument must be a positive number of seconds"),
+        },
+        None => Duration::from_secs(30),
+        _ => func_err!("'timeout' argument must be a positive number of seconds"),
+    };
+
+    let mut request = ureq::request(&method, url).timeout(timeout);
+    match args.get("token_env") {
+        Some(Value::String(name)) => {
+            let token = read_env_var(name, None)?;
+            request = request.set(
+                "Authorization",
+                &format!("Bearer {}", token.as_str().unwrap_or_default()),
+            );
+        }
+        None => {}
+        _ => func_err!("'token_env' argument must be the name of an environment variable"),
+    }
+    match args.get("headers") {
+        Some(Value::Object(headers)) => {
+            for (name, value) in headers {
+                let Some(value) = value.as_str() else {
+                    func_err!("Header '{}' must be a string", name);
+                };
+                request = request.set(name, &expand_env_vars(value)?);
+            }
+        }
+        None => {}
+        _ => func_err!("'headers' argument must be an object of strings"),
+    }
+
+    let result = match args.get("body") {
+        None => request.call(),
+        Some(Value::String(body)) => request.send_string(body),
+        Some(body) => {
+            if request.header("Content-Type").is_none() {
+                request = request.set("Content-Type", "application/json");
+            }
+            request.send_string(&body.to_string())
+        }
+    };
+    let response = match result {
+        Ok(response) => response,
+        Err(ureq::Error::Status(code, response)) => {
+            func_err!(
+                "{} {} returned {} {}",
+                method,
+                url,
+                code,
+                response.status_text()
+            )
+        }
+        Err(e) => func_err!("{} {} failed: {}", method, url, e),
+    };
+
+    let is_json = response.content_type().contains("json");
+    let body = match response.into_string() {
+        Ok(body) => body,
+        Err(e) => func_err!("Could not read the response from {}: {}", url, e),
+    };
+    if !is_json {
+        return Ok(Value::String(body));
+    }
+    let document: Value = match serde_json::from_str(&body) {
+        Ok(document) => document,
+        Err(e) => func_err!("Invalid JSON from {}: {}", url, e),
+    };
+    match args.get("pointer") {
+        None => Ok(document),
+        Some(Value::String(pointer)) => match document.pointer(pointer) {
+            Some(value) => Ok(value.clone()),
+            None => func_err!(
+                "Pointer '{}' matches nothing in the response from {}",
+                pointer,
+                url
+            ),
+        },
+        _ => func_err!("'pointer' argument must be a string such as \"/items\""),
+    }
+}
+
+/// Встроенная функция `now(format, utc, offset)`
+/// Возвращает текущее время, отформатированное strftime-шаблоном `format`
+/// (по умолчанию RFC 3339, например "2024-05-01T12:30:00+02:00"). Время берётся в локальном
+/// часовом поясе, в UTC при `utc: true` или со смещением `offset` вида "+05:30".
+pub fn now(args: &Map<String, Value>) -> Result<Value, Value> {
+    let format = match args.get("format") {
+        Some(Value::String(format)) => format.as_str(),
+        None => "%Y-%m-%dT%H:%M:%S%:z",
+        _ => func_err!("'format' argument must be a string"),
+    };
+    let utc = match args.get("utc") {
+        Some(Value::Bool(b)) => *b,
+        None => false,
+        _ => func_err!("'utc' argument must be a boolean (true or false)"),
+    };
+    let offset = match args.get("offset") {
+        Some(Value::String(offset)) => match offset.parse::<FixedOffset>() {
+            Ok(offset) => Some(offset),
+            Err(_) => func_err!("Invalid 'offset' '{}', expected e.g. \"+02:00\"", offset),
+        },
+        None => None,
+        _ => func_err!("'offset' argument must be a string such as \"+02:00\""),
+    };
+    if utc && offset.is_some() {
+        func_err!("'utc' and 'offset' cannot be used together");
+    }
+
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        func_err!("Invalid date format '{}'", format);
+    }
+    let formatted = match offset {
+        Some(offset) => Utc::now()
+            .with_timezone(&offset)
+            .format_with_items(items.into_iter())
+            .to_string(),
+        None if utc => Utc::now().format_with_items(items.into_iter()).to_string(),
+        None => Local::now()
+            .format_with_items(items.into_iter())
+            .to_string(),
+    };
+    Ok(Value::String(formatted))
+}
+
+/// Встроенная функция `glob(pattern)`
+/// Возвращает массив объектов {name, path, absolute_path, is_dir} для путей, подходящих
+/// под шаблон (`*`, `?`, `[...]`, `**` для любой глубины). Содержимое файлов не читается.
+pub fn glob(args: &Map<String, Value>) -> Result<Value, Value> {
+    let pattern = match args.get("pattern") {
+        Some(Value::String(pattern)) => pattern,
+        _ => func_err!(
+            "'glob' function requires a 'pattern' string argument (e.g., pattern: \"src/**/*.rs\")"
+        ),
+    };
+    let paths = match glob::glob(pattern) {
+        Ok(paths) => paths,
+        Err(e) => func_err!("Invalid glob pattern '{}': {}", pattern, e),
+    };
+
+    let mut result_paths = Vec::new();
+    for entry in paths {
+        let path = match entry {
+            Ok(path) => path,
+            Err(e) => {
+                warnings::warn(format!("Skipping path due to error: {}", e));
+                continue;
+            }
+        };
+        let absolute_path = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+
+        let mut path_obj = Map::new();
+        path_obj.insert(
+            "name".to_string(),
+            Value::String(
+                path.file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+        );
+        path_obj.insert(
+            "path".to_string(),
+            Value::String(path.to_string_lossy().to_string()),
+        );
+        path_obj.insert(
+            "absolute_path".to_string(),
+            Value::String(absolute_path.to_string_lossy().to_string()),
+        );
+        path_obj.insert("is_dir".to_string(), Value::Bool(path.is_dir()));
+        result_paths.push(Value::Object(path_obj));
+    }
+
+    Ok(Value::Array(result_paths))
+}
+
+/// Встроенная функция `csv(path, delimiter, header)`
+/// Читает CSV-файл. С заголовком (по умолчанию) возвращает массив объектов, ключи которых
+/// взяты из первой строки; с `header: false` - массив массивов строк.
+pub fn csv(args: &Map<String, Value>) -> Result<Value, Value> {
+    let path = match args.get("path") {
+        Some(Value::String(path)) => path,
+        _ => func_err!(
+            "'csv' function requires a 'path' string argument (e.g., path: \"users.csv\")"
+        ),
+    };
+    let delimiter = match args.get("delimiter") {
+        Some(Value::String(d)) if d.len() == 1 => d.as_bytes()[0],
+        None => b',',
+        _ => func_err!("'delimiter' argument must be a single character such as \";\""),
+    };
+    let has_header = match args.get("header") {
+        Some(Value::Bool(b)) => *b,
+        None => true,
+        _ => func_err!("'header' argument must be a boolean (true or false)"),
+    };
+
+    let mut reader = match csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(has_header)
+        .flexible(true)
+        .from_path(path)
+    {
+        Ok(reader) => reader,
+        Err(e) => func_err!("Could not open CSV file '{}': {}", path, e),
+    };
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(e) => func_err!("Could not read CSV file '{}': {}", path, e),
+    };
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => func_err!("Could not read CSV file '{}': {}", path, e),
+        };
+        if has_header {
+            // Недостающие в строке поля становятся пустыми строками, лишние - отбрасываются
+            let mut row_obj = Map::new();
+            for (i, key) in headers.iter().enumerate() {
+                let field = record.get(i).unwrap_or_default();
+                row_obj.insert(key.to_string(), Value::String(field.to_string()));
+            }
+            rows.push(Value::Object(row_obj));
+        } else {
+            let fields = record.iter().map(|field| Value::String(field.to_string()));
+            rows.push(Value::Array(fields.collect()));
+        }
+    }
+
+    Ok(Value::Array(rows))
+}
+
+/// Общая часть `json_file`, `yaml_file` и `toml_file`: читает файл из `path`, разбирает его
+/// функцией `parse` и применяет JSON Pointer из `pointer`, если он передан.
+fn load_data_file(
+    args: &Map<String, Value>,
+    func_name: &str,
+    format_name: &str,
+    parse: fn(&str) -> Result<Value, String>,
+) -> Result<Value, Value> {
+    let path = match args.get("path") {
+        Some(Value::String(path)) => path,
+        _ => func_err!("'{}' function requires a 'path' string argument", func_name),
+    };
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => func_err!("Could not read file '{}': {}", path, e),
+    };
+    let document = match parse(&content) {
+        Ok(document) => document,
+        Err(e) => func_err!("Invalid {} in '{}': {}", format_name, path, e),
+    };
+
+    match args.get("pointer") {
+        None => Ok(document),
+        Some(Value::String(pointer)) => match document.pointer(pointer) {
+            Some(value) => Ok(value.clone()),
+            None => func_err!("Pointer '{}' matches nothing in '{}'", pointer, path),
+        },
+        _ => func_err!("'pointer' argument must be a string such as \"/results\""),
+    }
+}
+
+/// Встроенная функция `json_file(path, pointer)`
+/// Читает и разбирает JSON-файл. `pointer` (JSON Pointer, например "/results/0")
+/// выбирает часть документа; несуществующий путь - ошибка.
+pub fn json_file(args: &Map<String, Value>) -> Result<Value, Value> {
+    load_data_file(args, "json_file", "JSON", |content| {
+        serde_json::from_str(content).map_err(|e| e.to_string())
+    })
+}
+
+/// Встроенная функция `yaml_file(path, pointer)`
+/// Как `json_file`, но для YAML. Ключи словарей должны быть строками.
+pub fn yaml_file(args: &Map<String, Value>) -> Result<Value, Value> {
+    load_data_file(args, "yaml_file", "YAML", |content| {
+        serde_yaml::from_str(content).map_err(|e| e.to_string())
+    })
+}
+
+/// Встроенная функция `toml_file(path, pointer)`
+/// Как `json_file`, но для TOML. Даты и время становятся строками.
+pub fn toml_file(args: &Map<String, Value>) -> Result<Value, Value> {
+    load_data_file(args, "toml_file", "TOML", |content| {
+        toml::from_str(content)
+            .map(toml_to_json)
+            .map_err(|e| e.to_string())
+    })
+}
+
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(dt) => Value::String(dt.to_string()),
+        toml::Value::Array(arr) => Value::Array(arr.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Встроенная функция `lines(path, skip_empty)`
+/// Возвращает строки файла массивом строк без символов перевода строки.
+/// С `skip_empty: true` пропускает пустые строки и строки только из пробелов.
+pub fn lines(args: &Map<String, Value>) -> Result<Value, Value> {
+    let path = match args.get("path") {
+        Some(Value::String(path)) => path,
+        _ => func_err!(
+            "'lines' function requires a 'path' string argument (e.g., path: \"TODO.txt\")"
+        ),
+    };
+    let skip_empty = match args.get("skip_empty") {
+        Some(Value::Bool(b)) => *b,
+        None => false,
+        _ => func_err!("'skip_empty' argument must be a boolean (true or false)"),
+    };
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => func_err!("Could not read file '{}': {}", path, e),
+    };
+
+    Ok(Value::Array(
+        content
+            .lines()
+            .filter(|line| !skip_empty || !line.trim().is_empty())
+            .map(|line| Value::String(line.to_string()))
+            .collect(),
+    ))
+}
+
+/// Не даёт опечатке вроде `end: 1000000000` занять всю память
+const MAX_RANGE_LEN: u64 = 1_000_000;
+
+/// Встроенная функция `range(start, end, step)`
+/// Возвращает массив целых чисел от `start` (по умолчанию 0) до `end` включительно
+/// с шагом `step` (по умолчанию 1, может быть отрицательным).
+pub fn range(args: &Map<String, Value>) -> Result<Value, Value> {
+    let int_arg = |name: &str, default: Option<i64>| match args.get(name) {
+        Some(Value::Number(n)) if n.is_i64() => Ok(n.as_i64().unwrap()),
+        None => default.ok_or_else(|| {
+            Value::String(
+                "'range' function requires an 'end' integer argument (e.g., end: 10)".into(),
+            )
+        }),
+        _ => func_err!("'{}' argument must be an integer", name),
+    };
+    let start = int_arg("start", Some(0))?;
+    let end = int_arg("end", None)?;
+    let step = int_arg("step", Some(1))?;
+    if step == 0 {
+        func_err!("'step' argument must not be 0");
+    }
+
+    let len = if (step > 0 && start > end) || (step < 0 && start < end) {
+        0
+    } else {
+        end.abs_diff(start) / step.unsigned_abs() + 1
+    };
+    if len > MAX_RANGE_LEN {
+        func_err!(
+            "range of {} numbers is too long (at most {})",
+            len,
+            MAX_RANGE_LEN
+        );
+    }
+
+    (0..len as i64)
+        .map(|i| {
+            i.checked_mul(step)
+                .and_then(|offset| start.checked_add(offset))
+                .map(Value::from)
+                .ok_or_else(|| {
+                    Value::String(format!(
+                        "range from {} with step {} is out of the 64-bit integer range",
+                        start, step
+                    ))
+                })
+        })
+        .collect::<Result<_, _>>()
+        .map(Value::Array)
+}
+
+/// Рекурсивно строит узел {name, path, is_dir, children} и дописывает его строки в `ascii`.
+/// `prefix` - отступ из "│   " и "    " для строк потомков.
+fn tree_node(
+    path: &Path,
+    depth_left: Option<usize>,
+    hidden: bool,
+    prefix: &str,
+    ascii: &mut String,
+) -> Value {
+    let mut children = Vec::new();
+    // Ссылки на каталоги не раскрываются, иначе ссылка на родителя зациклит обход
+    let expand = path.is_dir() && (prefix.is_empty() || !path.is_symlink());
+    if expand && depth_left != Some(0) {
+        let mut entries: Vec<_> = match fs::read_dir(path) {
+            Ok(entries) => entries.filter_map(Result::ok).map(|e| e.path()).collect(),
+            Err(e) => {
+                warnings::warn(format!(
+                    "Could not read directory {}: {}",
+                    path.display(),
+                    e
+                ));
+                Vec::new()
+            }
+        };
+        entries.retain(|p| {
+            hidden
+                || !p
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .starts_with('.')
+        });
+        entries.sort();
+
+        for (i, child) in entries.iter().enumerate() {
+            let is_last = i + 1 == entries.len();
+            let name = child.file_name().unwrap_or_default().to_string_lossy();
+            ascii.push_str(&format!(
+                "\n{}{}{}",
+                prefix,
+                if is_last { "└── " } else { "├── " },
+                name
+            ));
+            let child_prefix = format!("{}{}", prefix, if is_last { "    " } else { "│   " });
+            children.push(tree_node(
+                child,
+                depth_left.map(|d| d - 1),
+                hidden,
+                &child_prefix,
+                ascii,
+            ));
+        }
+    }
+
+    let mut node = Map::new();
+    node.insert(
+        "name".to_string(),
+        Value::String(
+            path.file_name()
+                .map_or_else(|| path.to_string_lossy(), |n| n.to_string_lossy())
+                .to_string(),
+        ),
+    );
+    node.insert(
+        "path".to_string(),
+        Value::String(path.to_string_lossy().to_string()),
+    );
+    node.insert("is_dir".to_string(), Value::Bool(path.is_dir()));
+    node.insert("children".to_string(), Value::Array(children));
+    Value::Object(node)
+}
+
+/// Встроенная функция `tree(path, depth, hidden)`
+/// Возвращает дерево каталога `path` (по умолчанию ".") из узлов {name, path, is_dir, children}.
+/// У корня есть поле `ascii` с готовым текстом в стиле `tree -L depth`.
+/// Скрытые файлы и каталоги пропускаются, если не передан `hidden: true`.
+pub fn tree(args: &Map<String, Value>) -> Result<Value, Value> {
+    let root = match args.get("path") {
+        Some(Value::String(path)) => path.as_str(),
+        None => ".",
+        _ => func_err!("'path' argument must be a string"),
+    };
+    let depth = optional_count(args, "depth")?;
+    let hidden = match args.get("hidden") {
+        Some(Value::Bool(b)) => *b,
+        None => false,
+        _ => func_err!("'hidden' argument must be a boolean (true or false)"),
+    };
+    let root = Path::new(root);
+    if !root.exists() {
+        func_err!("Path '{}' does not exist", root.display());
+    }
+
+    let mut ascii = root.to_string_lossy().to_string();
+    let mut node = tree_node(root, depth, hidden, "", &mut ascii);
+    if let Value::Object(node) = &mut node {
+        node.insert("ascii".to_string(), Value::String(ascii));
+    }
+    Ok(node)
+}
+
+/// Встроенная функция `clipboard()`
+/// Возвращает текст из системного буфера обмена. Пустой буфер или буфер без текста - ошибка.
+pub fn clipboard(_args: &Map<String, Value>) -> Result<Value, Value> {
+    let text = arboard::Clipboard::new().and_then(|mut clipboard| clipboard.get_text());
+    match text {
+        Ok(text) => Ok(Value::String(text)),
+        Err(e) => func_err!("Could not read the clipboard: {}", e),
+    }
+}
+
+/// Ввод читается один раз: повторные вызовы `stdin()` возвращают тот же текст
+static STDIN_TEXT: OnceLock<String> = OnceLock::new();
+
+/// Встроенная функция `stdin()`
+/// Возвращает весь переданный через pipe стандартный ввод. Если ввод - терминал, это ошибка,
+/// чтобы шаблон не ждал молча ввода с клавиатуры.
+pub fn stdin(_args: &Map<String, Value>) -> Result<Value, Value> {
+    if let Some(text) = STDIN_TEXT.get() {
+        return Ok(Value::String(text.clone()));
+    }
+    if io::stdin().is_terminal() {
+        func_err!("'stdin' function needs piped input, e.g. `git diff | runtpl run review`");
+    }
+    let mut text = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut text) {
+        func_err!("Could not read stdin: {}", e);
+    }
+    Ok(Value::String(STDIN_TEXT.get_or_init(|| text).clone()))
+}
+
+/// Встроенная функция `token_count(text, encoding)`
+/// Возвращает количество токенов в `text` для кодировки "o200k" (по умолчанию) или "cl100k".
+pub fn token_count(args: &Map<String, Value>) -> Result<Value, Value> {
+    let text = match args.get("text") {
+        Some(Value::String(text)) => text,
+        _ => func_err!("'token_count' function requires a 'text' string argument"),
+    };
+    let encoding = match args.get("encoding") {
+        Some(Value::String(encoding)) => encoding.as_str(),
+        None => "o200k",
+        _ => func_err!("'encoding' argument must be a string"),
+    };
+    match filters::count_tokens(text, encoding) {
+        Ok(count) => Ok(Value::from(count)),
+        Err(e) => func_err!("{}", e),
+    }
+}
+
+/// Ответы `prompt()` по тексту вопроса, чтобы один вопрос в нескольких тегах задавался один раз
+static PROMPT_ANSWERS: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+/// Встроенная функция `prompt(message, default)`
+/// Задаёт вопрос в терминале (через stderr, чтобы не смешивать с выводом) и возвращает ответ.
+/// Пустой ответ или stdin не из терминала дают `default` (по умолчанию пустую строку).
+pub fn prompt(args: &Map<String, Value>) -> Result<Value, Value> {
+    let message = match args.get("message") {
+        Some(Value::String(message)) => message,
+        _ => func_err!(
+            "'prompt' function requires a 'message' string argument (e.g., message: \"Ticket ID?\")"
+        ),
+    };
+    let default = match args.get("default") {
+        Some(Value::String(default)) => default.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    };
+
+    let mut answers = PROMPT_ANSWERS.lock().unwrap_or_else(|e| e.into_inner());
+    let answers = answers.get_or_insert_with(HashMap::new);
+    if let Some(answer) = answers.get(message) {
+        return Ok(Value::String(answer.clone()));
+    }
+    if !io::stdin().is_terminal() {
+        answers.insert(message.clone(), default.clone());
+        return Ok(Value::String(default));
+    }
+
+    if default.is_empty() {
+        eprint!("{} ", message);
+    } else {
+        eprint!("{} [{}] ", message, default);
+    }
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    if let Err(e) = io::stdin().read_line(&mut answer) {
+        func_err!("Could not read the answer: {}", e);
+    }
+    let answer = answer.trim_end_matches(['\r', '\n']);
+    let answer = if answer.is_empty() {
+        default
+    } else {
+        answer.to_string()
+    };
+
+    answers.insert(message.clone(), answer.clone());
+    Ok(Value::String(answer))
+}
+
+/// Разбирает значение из .env: кавычки снимаются (в двойных понимаются \n, \t, \" и \\),
+/// у значения без кавычек отбрасывается комментарий после " #".
+fn parse_dotenv_value(raw: &str) -> String {
+    let raw = raw.trim();
+    if let Some(inner) = raw.strip_prefix('\'').and_then(|r| r.split_once('\'')) {
+        return inner.0.to_string();
+    }
+    if let Some(inner) = raw.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = inner.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '"' => break,
+                '\\' => match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(other) => value.push(other),
+                    None => value.push('\\'),
+                },
+                c => value.push(c),
+            }
+        }
+        return value;
+    }
+    raw.split_once(" #")
+        .map_or(raw, |(value, _)| value)
+        .trim_end()
+        .to_string()
+}
+
+/// Встроенная функция `dotenv(path)`
+/// Читает .env-файл (по умолчанию ".env") и возвращает массив объектов {key, value, comment}
+/// в порядке файла. `comment` - строки комментариев прямо над переменной (без "#") или null.
+pub fn dotenv(args: &Map<String, Value>) -> Result<Value, Value> {
+    let path = match args.get("path") {
+        Some(Value::String(path)) => path.as_str(),
+        None => ".env",
+        _ => func_err!("'path' argument must be a string"),
+    };
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => func_err!("Could not read file '{}': {}", path, e),
+    };
+
+    let mut vars = Vec::new();
+    let mut comment_lines: Vec<&str> = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            comment_lines.clear();
+            continue;
+        }
+        if let Some(comment) = line.strip_prefix('#') {
+            comment_lines.push(comment.trim());
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, raw_value)) = line.split_once('=') else {
+            warnings::warn(format!("Skipping line without '=' in '{}': {}", path, line));
+            comment_lines.clear();
+            continue;
+        };
+
+        let mut var_obj = Map::new();
+        var_obj.insert("key".to_string(), Value::String(key.trim().to_string()));
+        var_obj.insert(
+            "value".to_string(),
+            Value::String(parse_dotenv_value(raw_value)),
+        );
+        let comment = (!comment_lines.is_empty()).then(|| comment_lines.join("\n"));
+        var_obj.insert("comment".to_string(), optional_string(comment));
+        vars.push(Value::Object(var_obj));
+        comment_lines.clear();
+    }
+
+    Ok(Value::Array(vars))
+}
+
+/// Встроенная функция `hash(path, text, algo)`
+/// Возвращает шестнадцатеричный дайджест файла `path` или строки `text`
+/// по алгоритму `algo`: "sha256" (по умолчанию), "sha1" или "md5".
+pub fn hash(args: &Map<String, Value>) -> Result<Value, Value> {
+    let algo = match args.get("algo") {
+        Some(Value::String(algo)) => algo.as_str(),
+        None => "sha256",
+        _ => func_err!("'algo' argument must be a string"),
+    };
+    let bytes = match (args.get("path"), args.get("text")) {
+        (Some(Value::String(path)), None) => match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) => func_err!("Could not read file '{}': {}", path, e),
+        },
+        (None, Some(Value::String(text))) => text.as_bytes().to_vec(),
+        _ => func_err!(
+            "'hash' function requires either a 'path' string (e.g., path: \"Cargo.lock\") or a 'text' string"
+        ),
+    };
+    match filters::hex_digest(&bytes, algo) {
+        Ok(digest) => Ok(Value::String(digest)),
+        Err(e) => func_err!("{}", e),
+    }
+}
+
+/// Встроенная функция `stats(path, text)`
+/// Возвращает `{chars, words, lines}` для файла `path` или строки `text`.
+pub fn stats(args: &Map<String, Value>) -> Result<Value, Value> {
+    match (args.get("path"), args.get("text")) {
+        (Some(Value::String(path)), None) => match fs::read(path) {
+            Ok(bytes) => Ok(filters::text_stats(&String::from_utf8_lossy(&bytes))),
+            Err(e) => func_err!("Could not read file '{}': {}", path, e),
+        },
+        (None, Some(Value::String(text))) => Ok(filters::text_stats(text)),
+        _ => func_err!(
+            "'stats' function requires either a 'path' string (e.g., path: \"README.md\") or a 'text' string"
+        ),
+    }
+}
+
+/// Переводит значение столбца SQLite в JSON; BLOB читается как текст с заменой невалидного UTF-8
+fn sqlite_value_to_json(value: rusqlite::types::ValueRef) -> Value {
+    use rusqlite::types::ValueRef;
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(i) => Value::from(i),
+        ValueRef::Real(f) => serde_json::Number::from_f64(f).map_or(Value::Null, Value::Number),
+        ValueRef::Text(bytes) | ValueRef::Blob(bytes) => {
+            Value::String(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+}
+
+/// Переводит JSON-параметр запроса в значение SQLite; массивы и объекты передаются как JSON-текст
+fn json_to_sqlite_value(value: &Value) -> rusqlite::types::Value {
+    use rusqlite::types::Value as SqlValue;
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => SqlValue::Real(n.as_f64().unwrap_or_default()),
+        },
+        Value::String(s) => SqlValue::Text(s.clone()),
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+/// Встроенная функция `sqlite(path, query, params)`
+/// Выполняет запрос к базе SQLite, открытой только для чтения, и возвращает строки результата
+/// в виде объектов с ключами по именам столбцов. `params` подставляются вместо `?` в запросе.
+pub fn sqlite(args: &Map<String, Value>) -> Result<Value, Value> {
+    let path = match args.get("path") {
+        Some(Value::String(path)) => path,
+        _ => func_err!(
+            "'sqlite' function requires a 'path' string argument (e.g., path: \"app.db\")"
+        ),
+    };
+    let query = match args.get("query") {
+        Some(Value::String(query)) => query,
+        _ => func_err!("'sqlite' function requires a 'query' string argument"),
+    };
+    let params: Vec<_> = match args.get("params") {
+        Some(Value::Array(params)) => params.iter().map(json_to_sqlite_value).collect(),
+        None => Vec::new(),
+        _ => func_err!("'params' argument must be an array"),
+    };
+
+    let conn = match rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    ) {
+        Ok(conn) => conn,
+        Err(e) => func_err!("Could not open SQLite database '{}': {}", path, e),
+    };
+    let mut stmt = match conn.prepare(query) {
+        Ok(stmt) => stmt,
+        Err(e) => func_err!("Invalid SQLite query: {}", e),
+    };
+    let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+
+    let mut rows = match stmt.query(rusqlite::params_from_iter(params)) {
+        Ok(rows) => rows,
+        Err(e) => func_err!("SQLite query failed: {}", e),
+    };
+    let mut result = Vec::new();
+    loop {
+        let row = match rows.next() {
+            Ok(Some(row)) => row,
+            Ok(None) => break,
+            Err(e) => func_err!("SQLite query failed: {}", e),
+        };
+        let mut row_obj = Map::new();
+        for (i, column) in columns.iter().enumerate() {
+            let value = row.get_ref(i).map_or(Value::Null, sqlite_value_to_json);
+            row_obj.insert(column.clone(), value);
+        }
+        result.push(Value::Object(row_obj));
+    }
+    Ok(Value::Array(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn args(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn files_from_overlapping_sources_appear_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let util = dir.path().join("util");
+        fs::create_dir(&util).unwrap();
+        fs::write(dir.path().join("main.rs"), "fn main() {}").unwrap();
+        fs::write(util.join("lib.rs"), "pub fn f() {}").unwrap();
+
+        let result = files(&args(json!({
+            "source": [dir.path(), util],
+            "content": false,
+        })))
+        .unwrap();
+        let names: Vec<_> = result
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, ["main.rs", "lib.rs"]);
+    }
+
+    #[test]
+    fn range_reports_overflow_instead_of_wrapping() {
+        assert_eq!(
+            range(&args(json!({ "start": 1, "end": 7, "step": 3 }))).unwrap(),
+            json!([1, 4, 7])
+        );
+        let err = range(&args(json!({
+            "start": i64::MIN,
+            "end": i64::MAX,
+            "step": i64::MAX,
+        })))
+        .unwrap_err();
+        assert_eq!(
+            err,
+            format!(
+                "range from {} with step {} is out of the 64-bit integer range",
+                i64::MIN,
+                i64::MAX
+            )
+        );
+    }
+
+    #[test]
+    fn head_lines_reads_the_first_lines_only() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("log.txt"), "1\n2\n3\n4\n5\n").unwrap();
+        fs::write(dir.path().join("short.txt"), "a\nb\n").unwrap();
+
+        let result = files(&args(json!({ "source": dir.path(), "head_lines": 3 }))).unwrap();
+        let files = result.as_array().unwrap();
+        assert_eq!(files[0]["content"], "1\n2\n3\n");
+        assert_eq!(files[0]["truncated"], true);
+        assert_eq!(files[1]["content"], "a\nb\n");
+        assert_eq!(files[1]["truncated"], false);
+    }
+}
@@ -0,0 +1,103 @@
+use serde_json::{Map, Value};
+use std::fmt;
+use std::str::FromStr;
+
+/// Access to the outside world that a built-in function needs. Templates
+/// can be shared between people, so each capability has to be granted
+/// explicitly (`--allow fs,net`) before functions needing it can be called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Capability {
+    /// Reading files and directories, including git repositories and databases.
+    Fs,
+    /// Reading environment variables.
+    Env,
+    /// Making network requests.
+    Net,
+    /// Running shell commands.
+    Exec,
+    /// Reading the clipboard.
+    Clipboard,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 5] = [
+        Capability::Fs,
+        Capability::Env,
+        Capability::Net,
+        Capability::Exec,
+        Capability::Clipboard,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Fs => "fs",
+            Capability::Env => "env",
+            Capability::Net => "net",
+            Capability::Exec => "exec",
+            Capability::Clipboard => "clipboard",
+        }
+    }
+
+    /// Parses a comma-separated list such as `fs,net`; `all` grants everything.
+    pub fn parse_list(list: &str) -> Result<Vec<Capability>, String> {
+        let mut capabilities = Vec::new();
+        for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            if name == "all" {
+                capabilities.extend(Capability::ALL);
+            } else {
+                capabilities.push(name.parse()?);
+            }
+        }
+        Ok(capabilities)
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Capability {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Capability::ALL
+            .into_iter()
+            .find(|capability| capability.name() == name)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown capability '{}', expected one of: {}, all",
+                    name,
+                    Capability::ALL.map(Capability::name).join(", ")
+                )
+            })
+    }
+}
+
+/// Capabilities the built-in function `name` needs for a call with `args`.
+/// Functions not listed here, such as `now()` or `range()`, need none.
+pub(crate) fn required_by(name: &str, args: &Map<String, Value>) -> Vec<Capability> {
+    match name {
+        "files" | "glob" | "csv" | "json_file" | "yaml_file" | "toml_file" | "lines" | "tree"
+        | "dotenv" | "sqlite" | "git" | "git_diff" => vec![Capability::Fs],
+        // Only with a file; hashing or measuring a string is harmless
+        "hash" | "stats" if args.contains_key("path") => vec![Capability::Fs],
+        "env" => vec![Capability::Env],
+        "fetch" => {
+            // `token_env` and `${NAME}` in headers send environment variables out
+            let reads_env = args.contains_key("token_env")
+                || args
+                    .get("headers")
+                    .is_some_and(|headers| headers.to_string().contains("${"));
+            if reads_env {
+                vec![Capability::Net, Capability::Env]
+            } else {
+                vec![Capability::Net]
+            }
+        }
+        "exec" => vec![Capability::Exec],
+        "clipboard" => vec![Capability::Clipboard],
+        _ => Vec::new(),
+    }
+}
@@ -1,4 +1,6 @@
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use clap_complete::Shell;
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "A powerful CLI templating tool")]
@@ -11,27 +13,28 @@ pub struct Cli {
 pub enum Commands {
     /// Renders a template with provided data
     #[command(name = "run")]
-    Run {
-        /// The name of the template file to use
-        template_name: String,
-
-        /// Data arguments in `key=value`, `key@=filepath`, or `key@-` format
-        #[arg()]
-        args: Vec<String>,
-
-        /// Enter interactive mode to fill variables
-        #[arg(short, long)]
-        interactive: bool,
-
-        /// Do not copy the output to the clipboard
-        #[arg(short = 'n', long = "no-copy")]
-        no_copy: bool,
-    },
+    Run(RunArgs),
+    /// List the filters available in templates
+    Filters,
     /// Manage templates
     Template {
         #[command(subcommand)]
         command: TemplateCommands,
     },
+    /// List the variables a template needs and their shape
+    Vars {
+        /// The name of the template file to analyze
+        template_name: String,
+
+        /// Print the variables as a JSON object instead of a list
+        #[arg(long)]
+        json: bool,
+    },
+    /// Print a shell completion script
+    Completions {
+        /// The shell to generate the script for
+        shell: Shell,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -41,7 +44,116 @@ pub enum TemplateCommands {
     /// Create a new template file
     New { name: String },
     /// Edit an existing template
-    Edit { name: String },
+    Edit {
+        name: String,
+
+        /// Create the template if it does not exist yet
+        #[arg(short, long)]
+        create: bool,
+    },
     /// Remove an existing template
     Remove { name: String },
 }
+
+#[derive(Args, Debug, Clone)]
+pub struct RunArgs {
+    /// The name of the template file to use
+    #[arg(required_unless_present = "job_stdin")]
+    pub template_name: Option<String>,
+
+    /// Data arguments in `key=value`, `key@=filepath`, or `key@-` format
+    #[arg()]
+    pub args: Vec<String>,
+
+    /// Enter interactive mode to fill variables
+    #[arg(short, long)]
+    pub interactive: bool,
+
+    /// Prompt for simple variables on the terminal instead of opening an editor
+    #[arg(long = "interactive-prompt", conflicts_with = "interactive")]
+    pub interactive_prompt: bool,
+
+    /// Nest all data arguments under this key (e.g. `user` turns `name=A` into `user.name`)
+    #[arg(long = "var-prefix", value_name = "KEY")]
+    pub var_prefix: Option<String>,
+
+    /// Read a `{"template": ..., "context": {...}}` job object from stdin
+    #[arg(
+        long = "job-stdin",
+        conflicts_with_all = ["template_name", "args", "interactive", "interactive_prompt"]
+    )]
+    pub job_stdin: bool,
+
+    /// Do not copy the output to the clipboard
+    #[arg(short = 'n', long = "no-copy")]
+    pub no_copy: bool,
+
+    /// Do not print informational notes such as the clipboard confirmation
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Write the output to this file instead of stdout (and not to the clipboard)
+    #[arg(short, long, value_name = "PATH", conflicts_with = "output_json")]
+    pub output: Option<PathBuf>,
+
+    /// Write the output to a file in this directory, named by `--output-name`
+    #[arg(
+        long = "output-dir",
+        value_name = "DIR",
+        requires = "output_name",
+        conflicts_with_all = ["output", "output_json"]
+    )]
+    pub output_dir: Option<PathBuf>,
+
+    /// Template for the file name inside `--output-dir`, e.g. "{{ item.slug }}.md"
+    #[arg(long = "output-name", value_name = "TEMPLATE", requires = "output_dir")]
+    pub output_name: Option<String>,
+
+    /// Render once per item of this collection, as `item`, into `--output-dir`
+    #[arg(long, value_name = "PATH", requires = "output_dir")]
+    pub each: Option<String>,
+
+    /// With `--each`, render the remaining items after one fails and report all failures
+    #[arg(long = "keep-going", short = 'k', requires = "each")]
+    pub keep_going: bool,
+
+    /// Overwrite output files that already exist
+    #[arg(long)]
+    pub force: bool,
+
+    /// Render again whenever the template or a `key@=path` data file changes
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["job_stdin", "interactive", "interactive_prompt", "output_json"]
+    )]
+    pub watch: bool,
+
+    /// Report undefined variables, function calls and the output size instead of rendering
+    #[arg(
+        long = "dry-run",
+        visible_alias = "check",
+        conflicts_with_all = ["interactive", "interactive_prompt", "output", "output_dir", "watch", "output_json"]
+    )]
+    pub dry_run: bool,
+
+    /// Print a JSON envelope with the output, warnings, or error instead of raw text
+    #[arg(long = "output-json", conflicts_with_all = ["interactive", "interactive_prompt"])]
+    pub output_json: bool,
+
+    /// Fail when the template outputs a variable that is not defined
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Fail when the template calls a function that does not exist
+    #[arg(long = "strict-functions")]
+    pub strict_functions: bool,
+
+    /// Grant capabilities to the template: fs, env, net, exec, clipboard or all
+    #[arg(long = "allow", value_name = "CAPS", action = clap::ArgAction::Append)]
+    pub allow: Vec<String>,
+
+    /// Same as `--allow exec`
+    #[arg(long = "allow-exec", hide = true)]
+    pub allow_exec: bool,
+}
@@ -0,0 +1,52 @@
+use crate::error::AppError;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// User settings from `config.toml` in the runtpl configuration directory.
+/// A missing file means the defaults.
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    /// External commands callable from templates as functions, by name:
+    /// `fns.jira = "jira-cli export --json"` makes `jira(...)` available.
+    #[serde(default)]
+    pub fns: HashMap<String, String>,
+    /// Shell commands usable as filters, by name: `filters.anonymize = "..."`
+    /// makes `{{ value | anonymize }}` available.
+    #[serde(default)]
+    pub filters: HashMap<String, String>,
+    /// Capabilities granted to every template, like `--allow`: `allow = ["fs"]`.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Fail on undefined variables by default, like `--strict`.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+/// Returns the runtpl directory inside the platform's configuration directory.
+pub(crate) fn app_dir() -> Result<PathBuf, AppError> {
+    let config_dir = dirs::config_dir().ok_or_else(|| {
+        AppError::Editor("Could not find a valid configuration directory.".to_string())
+    })?;
+    Ok(config_dir.join("runtpl"))
+}
+
+impl Config {
+    pub fn path() -> Result<PathBuf, AppError> {
+        Ok(app_dir()?.join(CONFIG_FILE))
+    }
+
+    pub fn load() -> Result<Config, AppError> {
+        let path = Config::path()?;
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let text = fs::read_to_string(&path)?;
+        toml::from_str(&text)
+            .map_err(|e| AppError::Config(format!("Invalid '{}': {}", path.display(), e)))
+    }
+}
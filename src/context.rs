@@ -1,5 +1,6 @@
+use crate::engine::resolve_path;
 use crate::error::AppError;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 use std::fs;
 use std::io::{self, Read};
@@ -24,15 +25,13 @@ impl Context {
                 let mut buffer = String::new();
                 io::stdin().read_to_string(&mut buffer)?;
                 let normalized = normalize_string(&buffer);
-                let value =
-                    serde_json::from_str(&normalized).unwrap_or_else(|_| Value::String(normalized));
+                let value = serde_json::from_str(&normalized).unwrap_or(Value::String(normalized));
                 context.0.insert(key.to_string(), value);
                 stdin_used = true;
             } else if let Some((key, path)) = arg.split_once("@=") {
                 let content = fs::read_to_string(path)?;
                 let normalized = normalize_string(&content);
-                let value =
-                    serde_json::from_str(&normalized).unwrap_or_else(|_| Value::String(normalized));
+                let value = serde_json::from_str(&normalized).unwrap_or(Value::String(normalized));
                 context.0.insert(key.to_string(), value);
             } else if let Some((key, value_str)) = arg.split_once('=') {
                 let normalized = normalize_string(value_str);
@@ -57,8 +56,39 @@ impl Context {
         Ok(context)
     }
 
+    /// Moves every key under `prefix`, e.g. `name` becomes `user.name` for prefix `user`.
+    /// A dotted prefix such as `app.user` nests one object per segment; empty
+    /// segments, as in `""`, `.user` or `app..user`, are rejected.
+    pub fn nest_under(self, prefix: &str) -> Result<Self, AppError> {
+        let segments: Vec<&str> = prefix.split('.').collect();
+        if segments.iter().any(|segment| segment.trim().is_empty()) {
+            return Err(AppError::InvalidArgument(format!(
+                "Invalid --var-prefix '{}': every dot-separated part must be a non-empty key.",
+                prefix
+            )));
+        }
+
+        let mut value = Value::Object(self.0.into_iter().collect());
+        for key in segments[1..].iter().rev() {
+            let mut wrapper = Map::new();
+            wrapper.insert(key.to_string(), value);
+            value = Value::Object(wrapper);
+        }
+        Ok(Context(HashMap::from([(segments[0].to_string(), value)])))
+    }
+
+    /// Looks up a dotted path such as `site.pages` or `items[0]`, like a template tag would.
+    pub fn lookup(&self, path: &str) -> Option<Value> {
+        let value = Value::Object(self.0.clone().into_iter().collect());
+        resolve_path(&value, path).cloned()
+    }
+
     pub fn from_interactive_json(json_str: &str) -> Result<Self, AppError> {
         let value: Value = serde_json::from_str(json_str)?;
+        Self::from_value(value)
+    }
+
+    pub fn from_value(value: Value) -> Result<Self, AppError> {
         match value {
             Value::Object(map) => {
                 let hash_map = map.into_iter().collect();
@@ -70,3 +100,49 @@ impl Context {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn nest_under_moves_every_arg_under_the_prefix() {
+        let context = Context::from_args(&args(&["name=Alice", "tags=a,b"]))
+            .unwrap()
+            .nest_under("user")
+            .unwrap();
+        assert_eq!(context.0.len(), 1);
+        assert_eq!(
+            context.0["user"],
+            json!({ "name": "Alice", "tags": ["a", "b"] })
+        );
+    }
+
+    #[test]
+    fn nest_under_dotted_prefix_nests_per_segment() {
+        let context = Context::from_args(&args(&["name=Alice"]))
+            .unwrap()
+            .nest_under("app.user")
+            .unwrap();
+        assert_eq!(context.lookup("app.user.name"), Some(json!("Alice")));
+    }
+
+    #[test]
+    fn nest_under_rejects_empty_segments() {
+        for prefix in ["", ".user", "user.", "app..user", " "] {
+            let result = Context::from_args(&args(&["name=Alice"]))
+                .unwrap()
+                .nest_under(prefix);
+            assert!(
+                matches!(result, Err(AppError::InvalidArgument(_))),
+                "prefix {:?} was accepted",
+                prefix
+            );
+        }
+    }
+}
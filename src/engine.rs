@@ -1,39 +1,146 @@
+use crate::blocks::{self, Block};
 use crate::builtin_fns;
+use crate::capability::{self, Capability};
 use crate::context::Context;
+use crate::expr;
+use crate::filters::{Filter, FilterFn, FilterRegistry};
+use crate::plugins::Plugin;
+use crate::script;
+use crate::template_manager;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use regex::{Captures, Regex};
-use serde_json::{Map, Value};
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::sync::Arc;
 
-type BuiltInFns = fn(&Map<String, Value>) -> Result<Value, Value>;
+/// Limit for nested `{{include}}`s and macro calls, which also stops
+/// templates including themselves or macros calling themselves forever.
+const MAX_NESTING_DEPTH: usize = 32;
+
+/// Built-in function callable as `name(key: value, ...)` in a `foreach` source.
+pub type BuiltInFn = fn(&Map<String, Value>) -> Result<Value, Value>;
 
 lazy_static! {
-    static ref RE_VAR: Regex = Regex::new(r"\{\{\s*([a-zA-Z0-9_.]+)\s*\}\}").unwrap();
+    static ref RE_VAR: Regex = Regex::new(
+        r#"\{\{\s*([a-zA-Z0-9_.\[\]]+|[a-zA-Z_][a-zA-Z0-9_]*\((?:[^()"]|"[^"]*")*\)(?:[.\[][a-zA-Z0-9_.\[\]]*)?|[a-zA-Z0-9_.\[\]\s()]*[-+*/%][a-zA-Z0-9_.\[\]\s+\-*/%()]*|(?:[^{}|?"]|"[^"]*")+\?(?:[^{}|:"]|"[^"]*")+:(?:[^{}|"]|"[^"]*")+)((?:\s*\|(?:[^|}"]|"[^"]*")*)*)\s*\}\}"#
+    ).unwrap();
     static ref RE_FOREACH: Regex = Regex::new(
-        r"(?m)(^\s*)\{\{foreach\s+([a-zA-Z0-9_]+)\s+in\s+([a-zA-Z0-9_.]+)(?:\(([^)]*)\))?\s*\}\}\s*?\r?\n?"
+        r"(?m)(^\s*)\{\{foreach\s+([a-zA-Z0-9_]+(?:\s*,\s*[a-zA-Z0-9_]+)?)\s+in\s+([a-zA-Z0-9_.\[\]]+)(?:\(([^)]*)\)(?:[.\[][a-zA-Z0-9_.\[\]]*)?)?(?:\s*\|[^}]*)?\s*\}\}\s*?\r?\n?"
     ).unwrap();
     static ref RE_ENDFOR: Regex = Regex::new(r"(?m)(^\s*)\{\{endfor\}\}\s*?\r?\n?").unwrap();
+    static ref RE_FOREACH_HEAD: Regex =
+        Regex::new(r"^([a-zA-Z0-9_]+)(?:\s*,\s*([a-zA-Z0-9_]+))?\s+in\s+(.+)$").unwrap();
+    static ref RE_FOREACH_SOURCE: Regex =
+        Regex::new(r"^([a-zA-Z0-9_.\[\]]+)(?:\((.*)\)([.\[][a-zA-Z0-9_.\[\]]*)?)?$").unwrap();
+    static ref RE_RAW: Regex = Regex::new(
+        r"(?ms)(^[ \t]*)?\{\{\s*raw\s*\}\}(.*?)(^[ \t]*)?\{\{\s*endraw\s*\}\}"
+    ).unwrap();
+    static ref RE_DIRECTIVE: Regex =
+        Regex::new(r"(?m)(^[ \t]*)?\{\{\s*(include|call|recurse|counter|break|continue)\b\s*([^}]*?)\s*\}\}(\r?\n)?").unwrap();
+    static ref RE_CALL_ARG_PATH: Regex =
+        Regex::new(r"[:]\s*([a-zA-Z_][a-zA-Z0-9_.]*)\s*(?:,|\)|$)").unwrap();
+    static ref RE_IDENTIFIER: Regex = Regex::new(r"^[a-zA-Z_][a-zA-Z0-9_]*$").unwrap();
+    static ref RE_MACRO_SIGNATURE: Regex =
+        Regex::new(r"^([a-zA-Z_][a-zA-Z0-9_]*)\s*\(([^)]*)\)$").unwrap();
+    static ref RE_COMMENT: Regex =
+        Regex::new(r"(?ms)^[ \t]*\{#.*?#\}[ \t]*(?:\r?\n|\z)|\{#.*?#\}").unwrap();
+    static ref RE_FUNCTION_CALL: Regex =
+        Regex::new(r#"^([a-zA-Z_][a-zA-Z0-9_]*)\(((?:[^()"]|"[^"]*")*)\)([.\[][a-zA-Z0-9_.\[\]]*)?$"#).unwrap();
 }
 
 lazy_static! {
-    static ref BUILTIN_FNS: HashMap<&'static str, BuiltInFns> = {
-        let mut m = HashMap::new();
-        m.insert("files", builtin_fns::files as BuiltInFns);
-        m
-    };
     static ref RESERVED_WORDS: HashSet<&'static str> = {
         let mut s = HashSet::new();
         s.insert("endfor");
         s.insert("in");
+        s.insert("loop");
+        s.insert("else");
+        s.insert("endif");
+        s.insert("include");
+        s.insert("endmacro");
+        s.insert("case");
+        s.insert("endswitch");
+        s.insert("endwith");
+        s.insert("endunless");
+        s.insert("endcapture");
+        s.insert("endsep");
+        s.insert("break");
+        s.insert("continue");
         s
     };
 }
 
-fn resolve_path<'a>(context: &'a Value, path: &str) -> Option<&'a Value> {
+fn raw_placeholder(index: usize) -> String {
+    format!("\u{1}raw:{}\u{1}", index)
+}
+
+fn strip_line_break(text: &str) -> &str {
+    text.strip_prefix("\r\n")
+        .or_else(|| text.strip_prefix('\n'))
+        .unwrap_or(text)
+}
+
+/// Replaces every `{{raw}}...{{endraw}}` section with a placeholder, so the
+/// engine never looks inside it. Returns the template and the raw contents,
+/// which `restore_raw_blocks` puts back after rendering.
+fn extract_raw_blocks(template: &str) -> (String, Vec<String>) {
+    let mut raw_blocks = Vec::new();
+    let mut result = String::with_capacity(template.len());
+    let mut last_end = 0;
+
+    for caps in RE_RAW.captures_iter(template) {
+        let whole = caps.get(0).unwrap();
+        result.push_str(&template[last_end..whole.start()]);
+
+        let mut content = &caps[2];
+        if caps.get(1).is_some() {
+            content = strip_line_break(content);
+        }
+        let mut end = whole.end();
+        if caps.get(3).is_some() {
+            end = template.len() - strip_line_break(&template[end..]).len();
+        }
+
+        result.push_str(&raw_placeholder(raw_blocks.len()));
+        raw_blocks.push(content.to_string());
+        last_end = end;
+    }
+    result.push_str(&template[last_end..]);
+
+    (result, raw_blocks)
+}
+
+fn restore_raw_blocks(rendered: String, raw_blocks: &[String]) -> String {
+    raw_blocks
+        .iter()
+        .enumerate()
+        .fold(rendered, |acc, (i, content)| {
+            acc.replace(&raw_placeholder(i), content)
+        })
+}
+
+/// Removes `{# ... #}` comments. A comment on a line of its own is removed
+/// together with that line.
+fn strip_comments(template: &str) -> Cow<'_, str> {
+    RE_COMMENT.replace_all(template, "")
+}
+
+/// Splits `items[0].name` or `items.0.name` into `items`, `0`, `name`.
+fn path_segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split(['.', '[', ']']).filter(|key| !key.is_empty())
+}
+
+pub(crate) fn resolve_path<'a>(context: &'a Value, path: &str) -> Option<&'a Value> {
     let mut current = context;
-    for key in path.split('.') {
-        current = current.get(key)?;
+    for key in path_segments(path) {
+        current = match current {
+            Value::Array(arr) => arr.get(key.parse::<usize>().ok()?)?,
+            _ => current.get(key)?,
+        };
     }
     Some(current)
 }
@@ -60,12 +167,9 @@ fn resolve_arg_value(val_str: &str, context: &Value) -> Result<Value, String> {
     ))
 }
 
-fn parse_function_args(args_str: &str, context: &Value) -> Result<Map<String, Value>, String> {
-    let mut args_map = Map::new();
-    if args_str.trim().is_empty() {
-        return Ok(args_map);
-    }
-
+/// Splits a comma-separated argument list, keeping commas inside quotes and
+/// brackets intact.
+fn split_args(args_str: &str) -> Vec<String> {
     let mut parts = Vec::new();
     let mut current = String::new();
     let mut bracket_level = 0;
@@ -94,8 +198,16 @@ fn parse_function_args(args_str: &str, context: &Value) -> Result<Map<String, Va
     if !current.trim().is_empty() {
         parts.push(current.trim().to_string());
     }
+    parts
+}
 
-    for part in parts {
+fn parse_function_args(args_str: &str, context: &Value) -> Result<Map<String, Value>, String> {
+    let mut args_map = Map::new();
+    if args_str.trim().is_empty() {
+        return Ok(args_map);
+    }
+
+    for part in split_args(args_str) {
         let mut kv = part.splitn(2, ':');
         let key = kv
             .next()
@@ -112,19 +224,285 @@ fn parse_function_args(args_str: &str, context: &Value) -> Result<Map<String, Va
     Ok(args_map)
 }
 
-fn render_variables(template: &str, context: &Value) -> String {
-    RE_VAR
-        .replace_all(template, |caps: &Captures| {
-            let path = &caps[1];
-            resolve_path(context, path)
-                .map(value_to_string)
-                .unwrap_or_default()
+/// A `{{macro name(params)}}...{{endmacro}}` definition.
+struct Macro {
+    params: Vec<String>,
+    body: String,
+}
+
+/// A pending `{{break}}` or `{{continue}}`, handled by the innermost loop.
+#[derive(Clone, Copy, PartialEq)]
+enum LoopFlow {
+    Break,
+    Continue,
+}
+
+/// Mutable bookkeeping for a single `render` call.
+#[derive(Default)]
+struct RenderState {
+    /// Current nesting of includes and macro calls.
+    depth: usize,
+    /// Number of `foreach` loops currently being rendered.
+    loop_depth: usize,
+    /// Set by `{{break}}`/`{{continue}}`; everything up to the end of the
+    /// current iteration is skipped while it is set.
+    flow: Option<LoopFlow>,
+    macros: HashMap<String, Macro>,
+    /// Names of the macros being rendered, innermost last, for `{{recurse}}`.
+    macro_stack: Vec<String>,
+    /// Values of the `{{counter "name"}}` counters, shared by the whole render.
+    counters: HashMap<String, u64>,
+    /// Variables of output tags that were missing from the context, with the
+    /// tag they appeared in, in `strict_variables` mode.
+    undefined: Vec<(String, String)>,
+    /// Set by `Engine::dry_run`: functions are recorded in `calls` instead of
+    /// being called, and undefined variables are recorded as in strict mode.
+    dry_run: bool,
+    calls: Vec<PlannedCall>,
+}
+
+/// Fails with every variable recorded in `state.undefined`.
+fn check_undefined(template: &str, state: &RenderState) -> Result<(), String> {
+    let list = locate_undefined(template, state);
+    match list.as_slice() {
+        [] => Ok(()),
+        [single] => Err(format!("Undefined variable {}", single)),
+        _ => Err(format!("Undefined variables: {}", list.join("; "))),
+    }
+}
+
+/// Describes the variables recorded in `state.undefined`, located by the
+/// first occurrence of their tag in `template`. Tags from included templates
+/// and helpers files are described without a location.
+fn locate_undefined(template: &str, state: &RenderState) -> Vec<String> {
+    state
+        .undefined
+        .iter()
+        .map(|(name, tag)| match template.find(tag.as_str()) {
+            Some(offset) => {
+                let before = &template[..offset];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                format!("'{}' at line {}, column {}", name, line, column)
+            }
+            None => format!("'{}' in '{}'", name, tag),
         })
-        .into_owned()
+        .collect()
 }
 
-/// Enum for describing variables
+/// Parses the quoted argument of `{{include "name"}}` or `{{counter "name"}}`.
+fn parse_quoted_name(keyword: &str, args: &str) -> Result<String, String> {
+    serde_json::from_str(args)
+        .map_err(|_| format!("'{{{{{} {}}}}}' expects a quoted name", keyword, args))
+}
+
+fn parse_macro_signature(signature: &str) -> Result<(String, Vec<String>), String> {
+    let caps = RE_MACRO_SIGNATURE
+        .captures(signature.trim())
+        .ok_or_else(|| format!("Invalid macro signature: '{}'", signature))?;
+    let params = caps[2]
+        .split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(String::from)
+        .collect();
+    Ok((caps[1].to_string(), params))
+}
+
+fn read_included_template(name: &str) -> Result<String, String> {
+    let path = template_manager::resolve_template_path(name).map_err(|e| e.to_string())?;
+    fs::read_to_string(&path)
+        .map_err(|e| format!("Could not read included template '{}': {}", name, e))
+}
+
+/// Moves the top-level `{{macro}}` definitions of a template into `state`
+/// and returns the template without them.
+fn collect_macros(template: &str, state: &mut RenderState) -> Result<String, String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(block) = blocks::find_first_block(rest)? {
+        if block.keyword() == "macro" {
+            let (name, params) = parse_macro_signature(block.sections[0].tag.args)?;
+            let body = block.sections[0].body.to_string();
+            state.macros.insert(name, Macro { params, body });
+            result.push_str(&rest[..block.start]);
+        } else {
+            result.push_str(&rest[..block.end]);
+        }
+        rest = &rest[block.end..];
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Template engine configuration: the functions and filters available to
+/// templates and how strictly unknown names are treated.
+/// `Engine::default()` renders exactly like the CLI does without flags.
+#[derive(Debug, Clone)]
+pub struct Engine {
+    /// Fail on `name(...)` calls to functions that are not registered,
+    /// instead of leaving the tag in the output untouched.
+    pub strict_functions: bool,
+    /// Fail when output tags refer to variables missing from the context,
+    /// instead of rendering them as empty strings.
+    pub strict_variables: bool,
+    /// Capabilities granted to templates. None by default, so calls to
+    /// functions that read files, the environment or the network fail
+    /// until the capability they need is granted.
+    pub capabilities: HashSet<Capability>,
+    /// Filters usable in `{{ value | filter }}` tags.
+    pub filters: FilterRegistry,
+    /// Functions backed by external commands, by name. A call passes its
+    /// arguments to the command as a JSON object on stdin and evaluates to
+    /// the JSON the command prints. Built-in functions take precedence.
+    pub external_functions: HashMap<String, String>,
+    functions: HashMap<&'static str, BuiltInFn>,
+    plugin_functions: HashMap<String, Arc<dyn Plugin>>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        let mut functions = HashMap::new();
+        functions.insert("files", builtin_fns::files as BuiltInFn);
+        functions.insert("env", builtin_fns::env as BuiltInFn);
+        functions.insert("git", builtin_fns::git as BuiltInFn);
+        functions.insert("git_diff", builtin_fns::git_diff as BuiltInFn);
+        functions.insert("exec", builtin_fns::exec as BuiltInFn);
+        functions.insert("fetch", builtin_fns::fetch as BuiltInFn);
+        functions.insert("now", builtin_fns::now as BuiltInFn);
+        functions.insert("glob", builtin_fns::glob as BuiltInFn);
+        functions.insert("csv", builtin_fns::csv as BuiltInFn);
+        functions.insert("json_file", builtin_fns::json_file as BuiltInFn);
+        functions.insert("yaml_file", builtin_fns::yaml_file as BuiltInFn);
+        functions.insert("toml_file", builtin_fns::toml_file as BuiltInFn);
+        functions.insert("lines", builtin_fns::lines as BuiltInFn);
+        functions.insert("range", builtin_fns::range as BuiltInFn);
+        functions.insert("tree", builtin_fns::tree as BuiltInFn);
+        functions.insert("clipboard", builtin_fns::clipboard as BuiltInFn);
+        functions.insert("stdin", builtin_fns::stdin as BuiltInFn);
+        functions.insert("token_count", builtin_fns::token_count as BuiltInFn);
+        functions.insert("prompt", builtin_fns::prompt as BuiltInFn);
+        functions.insert("dotenv", builtin_fns::dotenv as BuiltInFn);
+        functions.insert("hash", builtin_fns::hash as BuiltInFn);
+        functions.insert("stats", builtin_fns::stats as BuiltInFn);
+        functions.insert("sqlite", builtin_fns::sqlite as BuiltInFn);
+
+        Engine {
+            strict_functions: false,
+            strict_variables: false,
+            capabilities: HashSet::new(),
+            filters: FilterRegistry::default(),
+            external_functions: HashMap::new(),
+            functions,
+            plugin_functions: HashMap::new(),
+        }
+    }
+}
+
+/// Fields of `files()` objects that need the file to be read.
+const FILE_CONTENT_FIELDS: [&str; 3] = ["content", "truncated", "line_count"];
+
+/// Whether a loop body might read the contents of its `files()` item: it
+/// uses one of `FILE_CONTENT_FIELDS`, or the item as a whole (`{{ file | json }}`,
+/// macro arguments and the like). Macros, includes and recursion see the item
+/// through the inherited scope, so a body using them always reads it.
+/// Errs on the side of reading.
+fn reads_file_content(item_name: &str, body: &str) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    let reaches_other_templates = RE_DIRECTIVE
+        .captures_iter(body)
+        .any(|caps| matches!(&caps[2], "call" | "include" | "recurse"));
+    reaches_other_templates
+        || body.match_indices(item_name).any(|(start, _)| {
+            let rest = &body[start + item_name.len()..];
+            if body[..start].chars().next_back().is_some_and(is_word_char)
+                || rest.chars().next().is_some_and(is_word_char)
+            {
+                return false;
+            }
+            let field = rest.strip_prefix('.').map(|rest| {
+                let end = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+                &rest[..end]
+            });
+            match field {
+                Some(field) if !field.is_empty() => FILE_CONTENT_FIELDS.contains(&field),
+                _ => true,
+            }
+        })
+}
+
+fn is_plain_path(expression: &str) -> bool {
+    expression
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "_.[]".contains(c))
+}
+
+/// Splits `source | filter | filter(args)` at the pipes outside of quotes and brackets.
+fn split_pipeline(expr: &str) -> Vec<&str> {
+    let mut stages = Vec::new();
+    let mut start = 0;
+    let mut depth = 0;
+    let mut in_quotes = false;
+    for (i, c) in expr.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            _ if in_quotes => {}
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '|' if depth == 0 => {
+                stages.push(expr[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    stages.push(expr[start..].trim());
+    stages
+}
+
+/// Parses one pipeline stage: `name`, `name:arg` or `name(arg, ...)`.
+fn parse_filter<'a>(filter: &'a str, context: &Value) -> Result<(&'a str, Vec<Value>), String> {
+    if let Some((name, args_str)) = filter
+        .strip_suffix(')')
+        .and_then(|filter| filter.split_once('('))
+    {
+        let args = split_args(args_str)
+            .iter()
+            .map(|arg| resolve_arg_value(arg, context))
+            .collect::<Result<_, _>>()?;
+        return Ok((name.trim(), args));
+    }
+    match filter.split_once(':') {
+        Some((name, arg_str)) => Ok((name.trim(), vec![resolve_arg_value(arg_str, context)?])),
+        None => Ok((filter, vec![])),
+    }
+}
+
+/// A function call a dry run came across, see `Engine::dry_run`.
 #[derive(Debug, Clone, PartialEq)]
+pub struct PlannedCall {
+    pub name: String,
+    pub args: Map<String, Value>,
+    /// Capabilities the call needs that haven't been granted.
+    pub missing_capabilities: Vec<Capability>,
+}
+
+/// What rendering a template would do, see `Engine::dry_run`.
+#[derive(Debug, Clone, Default)]
+pub struct DryRunReport {
+    /// Undefined variables with their location, like `--strict` reports them.
+    pub undefined: Vec<String>,
+    /// Function calls in the order they were reached, without duplicates.
+    pub calls: Vec<PlannedCall>,
+    /// Length in bytes of the output with every function result left empty.
+    pub estimated_size: usize,
+}
+
+/// Enum for describing variables
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "shape", content = "fields", rename_all = "snake_case")]
 pub enum VarUsage {
     /// Simple variable: {{ var }} -> "..."
     Simple,
@@ -135,22 +513,17 @@ pub enum VarUsage {
     CollectionOfObjects(HashMap<String, VarUsage>),
 }
 
-fn analyze_object_structure(
-    loop_body: &str,
-    item_var: &str,
-    all_loop_vars: &HashSet<String>,
-) -> HashMap<String, VarUsage> {
+fn analyze_object_structure(loop_body: &str, item_var: &str) -> HashMap<String, VarUsage> {
     let mut structure = HashMap::new();
 
     for caps in RE_FOREACH.captures_iter(loop_body) {
         let source_path = &caps[3];
 
         if let Some(prop_name) = source_path.strip_prefix(&format!("{}.", item_var)) {
-            let inner_loop_item_var = &caps[2];
+            let inner_loop_item_var = item_var_name(&caps[2]);
             let inner_loop_body = find_loop_body(loop_body, &caps[0]);
 
-            let sub_structure =
-                analyze_object_structure(&inner_loop_body, inner_loop_item_var, all_loop_vars);
+            let sub_structure = analyze_object_structure(&inner_loop_body, inner_loop_item_var);
 
             let usage = if sub_structure.is_empty() {
                 VarUsage::CollectionOfSimple
@@ -161,20 +534,28 @@ fn analyze_object_structure(
         }
     }
 
-    for caps in RE_VAR.captures_iter(loop_body) {
-        let path = &caps[1];
-        if let Some(prop_name) = path.strip_prefix(&format!("{}.", item_var)) {
-            if let Some(first_prop) = prop_name.split('.').next() {
-                structure
-                    .entry(first_prop.to_string())
-                    .or_insert(VarUsage::Simple);
-            }
+    let tag_paths = RE_VAR
+        .captures_iter(loop_body)
+        .flat_map(|caps| expr::expression_paths(caps.get(1).unwrap().as_str()));
+    for path in tag_paths {
+        if let Some(prop_name) = path.strip_prefix(&format!("{}.", item_var))
+            && let Some(first_prop) = path_segments(prop_name).next()
+        {
+            structure
+                .entry(first_prop.to_string())
+                .or_insert(VarUsage::Simple);
         }
     }
 
     structure
 }
 
+/// The variable holding each item in a `foreach` head's variable list:
+/// `item` in `item`, `value` in `key, value`.
+fn item_var_name(loop_vars: &str) -> &str {
+    loop_vars.rsplit(',').next().unwrap_or(loop_vars).trim()
+}
+
 fn find_loop_body(template_chunk: &str, start_tag: &str) -> String {
     if let Some(start_match) = RE_FOREACH.find(template_chunk) {
         if start_match.as_str() != start_tag {
@@ -207,142 +588,1304 @@ fn find_loop_body(template_chunk: &str, start_tag: &str) -> String {
     "".to_string()
 }
 
-pub fn extract_variables(template: &str) -> HashMap<String, VarUsage> {
-    let mut variables = HashMap::new();
+impl Engine {
+    pub fn render(&self, template: &str, context: &Context) -> Result<String, String> {
+        let mut state = RenderState::default();
+        let rendered = self.render_entry(template, None, context, &mut state)?;
+        check_undefined(template, &state)?;
+        Ok(rendered)
+    }
 
-    let all_loop_vars: HashSet<String> = RE_FOREACH
-        .captures_iter(template)
-        .map(|caps| caps[2].to_string())
-        .collect();
+    /// Renders `template` with the macros defined in `helpers` available, as
+    /// if they were defined in the template itself. The template's own macros
+    /// take precedence. `helpers` may contain nothing but macros and comments.
+    pub fn render_with_helpers(
+        &self,
+        template: &str,
+        helpers: &str,
+        context: &Context,
+    ) -> Result<String, String> {
+        let mut state = RenderState::default();
+        let rendered = self.render_entry(template, Some(helpers), context, &mut state)?;
+        check_undefined(template, &state)?;
+        Ok(rendered)
+    }
 
-    for caps in RE_FOREACH.captures_iter(template) {
-        let source_path = &caps[3];
-        if let Some(base_var) = source_path.split('.').next() {
-            if all_loop_vars.contains(base_var) {
-                continue;
+    /// Goes through `template` like `render_with_helpers` (or `render`, without
+    /// `helpers`), but only reports what rendering would do. No function is
+    /// called, so nothing is read, fetched or executed, and command and plugin
+    /// filters pass their input through unchanged.
+    pub fn dry_run(
+        &self,
+        template: &str,
+        helpers: Option<&str>,
+        context: &Context,
+    ) -> Result<DryRunReport, String> {
+        let mut state = RenderState {
+            dry_run: true,
+            ..RenderState::default()
+        };
+        let rendered = self.render_entry(template, helpers, context, &mut state)?;
+        Ok(DryRunReport {
+            undefined: locate_undefined(template, &state),
+            calls: state.calls,
+            estimated_size: rendered.len(),
+        })
+    }
+
+    /// Renders the entry template, with the macros of its helpers file if any.
+    fn render_entry(
+        &self,
+        template: &str,
+        helpers: Option<&str>,
+        context: &Context,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        if let Some(helpers) = helpers {
+            let rest = collect_macros(&strip_comments(helpers), state)?;
+            if !rest.trim().is_empty() {
+                return Err("Helper files may only contain macro definitions".to_string());
             }
+        }
 
-            let is_function_call = caps.get(4).is_some();
-            if is_function_call || BUILTIN_FNS.contains_key(source_path) {
+        let context_value = Value::Object(context.0.clone().into_iter().collect());
+        self.render_source(template, &context_value, state)
+    }
+
+    /// Renders a whole template file: the entry template or an included one.
+    fn render_source(
+        &self,
+        template: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let (template, raw_blocks) = extract_raw_blocks(template);
+        let template = strip_comments(&template);
+        let template = collect_macros(&template, state)?;
+        let rendered = self.render_recursive(&template, context, state)?;
+        Ok(restore_raw_blocks(rendered, &raw_blocks))
+    }
+
+    /// Runs `render` one nesting level deeper, failing once the limit is reached.
+    fn nested<F>(&self, what: &str, state: &mut RenderState, render: F) -> Result<String, String>
+    where
+        F: FnOnce(&mut RenderState) -> Result<String, String>,
+    {
+        if state.depth >= MAX_NESTING_DEPTH {
+            return Err(format!(
+                "Too much nesting at {} (does it include or call itself?)",
+                what
+            ));
+        }
+        state.depth += 1;
+        let rendered = render(state);
+        state.depth -= 1;
+        rendered
+    }
+
+    fn render_include(
+        &self,
+        name: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let content = read_included_template(name)?;
+        self.nested(&format!("include '{}'", name), state, |state| {
+            self.render_source(&content, context, state)
+        })
+    }
+
+    /// Renders `{{call name(param: value, ...)}}`: the macro body sees the
+    /// current context plus its parameters.
+    fn render_macro_call(
+        &self,
+        call: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let (name, args_str) = call
+            .strip_suffix(')')
+            .and_then(|call| call.split_once('('))
+            .ok_or_else(|| format!("Invalid macro call: '{}'", call))?;
+        let args = parse_function_args(args_str, context)?;
+        self.invoke_macro(name.trim(), args, context, state)
+    }
+
+    /// Renders `{{recurse value}}` or `{{recurse param: value, ...}}`: calls the
+    /// macro currently being rendered again. A single value goes to its first parameter.
+    fn render_recurse(
+        &self,
+        args_str: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let name = state
+            .macro_stack
+            .last()
+            .cloned()
+            .ok_or("'{{recurse}}' can only be used inside a macro")?;
+
+        let parts = split_args(args_str);
+        let args = match parts.as_slice() {
+            [value] if !value.contains(':') => {
+                let first_param = state.macros[&name]
+                    .params
+                    .first()
+                    .ok_or_else(|| format!("Macro '{}' has no parameter to recurse with", name))?;
+                // A leaf without children simply ends the recursion
+                let Some(value) = expr::resolve_operand(value, context) else {
+                    return Ok(String::new());
+                };
+                let mut args = Map::new();
+                args.insert(first_param.clone(), value);
+                args
+            }
+            _ => parse_function_args(args_str, context)?,
+        };
+        self.invoke_macro(&name, args, context, state)
+    }
+
+    fn invoke_macro(
+        &self,
+        name: &str,
+        args: Map<String, Value>,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let (params, body) = match state.macros.get(name) {
+            Some(m) => (m.params.clone(), m.body.clone()),
+            None => return Err(format!("Unknown macro '{}'", name)),
+        };
+
+        if let Some(unknown) = args.keys().find(|key| !params.contains(key)) {
+            return Err(format!(
+                "Macro '{}' has no parameter named '{}'",
+                name, unknown
+            ));
+        }
+
+        let mut macro_context = context.as_object().cloned().unwrap_or_default();
+        for param in &params {
+            match args.get(param) {
+                Some(value) => macro_context.insert(param.clone(), value.clone()),
+                None => macro_context.remove(param),
+            };
+        }
+        let macro_context = Value::Object(macro_context);
+
+        state.macro_stack.push(name.to_string());
+        let rendered = self.nested(&format!("macro '{}'", name), state, |state| {
+            self.render_recursive(&body, &macro_context, state)
+        });
+        state.macro_stack.pop();
+        rendered
+    }
+
+    /// Renders text that contains no blocks: directives such as `{{include}}`
+    /// and `{{call}}`, and variable tags.
+    fn render_variables(
+        &self,
+        template: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let mut result = String::with_capacity(template.len());
+        let mut last_end = 0;
+        for caps in RE_DIRECTIVE.captures_iter(template) {
+            let whole = caps.get(0).unwrap();
+            result.push_str(&self.render_tags(
+                &template[last_end..whole.start()],
+                context,
+                state,
+            )?);
+
+            if caps[3].is_empty() && matches!(&caps[2], "include" | "call" | "recurse" | "counter")
+            {
+                // Without an argument these are ordinary variables, e.g. `{{ counter }}`
+                result.push_str(&self.render_tags(whole.as_str(), context, state)?);
+                last_end = whole.end();
                 continue;
             }
 
-            let item_var = &caps[2];
-            let loop_body = find_loop_body(template, caps.get(0).unwrap().as_str());
+            let indent = caps.get(1).map_or("", |m| m.as_str());
+            let line_break = caps.get(4).map_or("", |m| m.as_str());
+            let included = match &caps[2] {
+                "include" => {
+                    self.render_include(&parse_quoted_name("include", &caps[3])?, context, state)?
+                }
+                "call" => self.render_macro_call(&caps[3], context, state)?,
+                "recurse" => self.render_recurse(&caps[3], context, state)?,
+                "counter" => {
+                    let count = state
+                        .counters
+                        .entry(parse_quoted_name("counter", &caps[3])?)
+                        .or_insert(0);
+                    *count += 1;
+                    count.to_string()
+                }
+                keyword => {
+                    if state.loop_depth == 0 {
+                        return Err(format!(
+                            "'{{{{{}}}}}' can only be used inside a foreach loop",
+                            keyword
+                        ));
+                    }
+                    if !caps[3].is_empty() {
+                        return Err(format!("'{{{{{}}}}}' takes no arguments", keyword));
+                    }
+                    state.flow = Some(match keyword {
+                        "break" => LoopFlow::Break,
+                        _ => LoopFlow::Continue,
+                    });
+                    // A standalone tag disappears with its line, an inline one keeps the text before it
+                    if caps.get(1).is_none() || caps.get(4).is_none() {
+                        result.push_str(indent);
+                    }
+                    return Ok(result);
+                }
+            };
+            if caps.get(1).is_some() && caps.get(4).is_some() {
+                // Standalone directive: the inserted text replaces the whole line
+                if &caps[2] == "recurse" {
+                    // Each level of a tree is indented like its `{{recurse}}` tag
+                    for line in included.split_inclusive('\n') {
+                        result.push_str(indent);
+                        result.push_str(line);
+                    }
+                } else {
+                    result.push_str(&included);
+                }
+                if !included.is_empty() && !included.ends_with('\n') {
+                    result.push_str(line_break);
+                }
+            } else {
+                // Inline directive: drop the final line break so the line continues
+                let included = included
+                    .strip_suffix("\r\n")
+                    .or_else(|| included.strip_suffix('\n'))
+                    .unwrap_or(&included);
+                result.push_str(indent);
+                result.push_str(included);
+                result.push_str(line_break);
+            }
+            if state.flow.is_some() {
+                return Ok(result);
+            }
+            last_end = whole.end();
+        }
+        result.push_str(&self.render_tags(&template[last_end..], context, state)?);
+        Ok(result)
+    }
 
-            let structure = analyze_object_structure(&loop_body, item_var, &all_loop_vars);
+    fn render_tags(
+        &self,
+        template: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let mut result = String::with_capacity(template.len());
+        let mut last_end = 0;
+        for caps in RE_VAR.captures_iter(template) {
+            let whole = caps.get(0).unwrap();
+            result.push_str(&template[last_end..whole.start()]);
+            match RE_FUNCTION_CALL.captures(caps[1].trim()) {
+                Some(call) if !self.has_function(&call[1]) => {
+                    if self.strict_functions {
+                        return Err(format!("Unknown function '{}'", &call[1]));
+                    }
+                    // Not ours to render, e.g. `{{ noww() }}` or another tool's syntax
+                    result.push_str(whole.as_str());
+                }
+                _ => result.push_str(&self.render_tag(&caps, context, state)?),
+            }
+            last_end = whole.end();
+        }
+        result.push_str(&template[last_end..]);
+        Ok(result)
+    }
 
-            let usage = if structure.is_empty() {
-                VarUsage::CollectionOfSimple
+    /// Resolves a single `{{ expression | filter | filter(args) ... }}` tag to its output text.
+    fn render_tag(
+        &self,
+        caps: &Captures,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let expression = caps[1].trim();
+        let mut value = self
+            .evaluate_output(expression, context, state)
+            .map_err(|e| format!("{} in '{{{{ {} }}}}'", e, expression))?;
+        let undefined = (self.strict_variables || state.dry_run)
+            && value.is_none()
+            && is_plain_path(expression);
+
+        for filter in split_pipeline(&caps[2])
+            .into_iter()
+            .filter(|f| !f.is_empty())
+        {
+            let (name, args) = parse_filter(filter, context)?;
+            let input = value.unwrap_or(Value::Null);
+            // A filter yielding `null` (e.g. `get` past the end) counts as a missing value
+            value = Some(self.apply_filter(name, &input, &args, state.dry_run)?)
+                .filter(|v| !v.is_null());
+        }
+
+        // `{{ name | default("x") }}` is fine without `name`
+        if undefined && value.is_none() {
+            let entry = (expression.to_string(), caps[0].to_string());
+            if !state.undefined.contains(&entry) {
+                state.undefined.push(entry);
+            }
+        }
+
+        Ok(value.as_ref().map(value_to_string).unwrap_or_default())
+    }
+
+    /// Evaluates the expression of an output tag: a path, a function call (optionally followed by a field path), a
+    /// ternary `cond ? a : b`, or arithmetic. `None` means a missing value.
+    fn evaluate_output(
+        &self,
+        expression: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<Option<Value>, String> {
+        if is_plain_path(expression) {
+            return Ok(resolve_path(context, expression).cloned());
+        }
+        if let Some(call) = RE_FUNCTION_CALL.captures(expression)
+            && self.has_function(&call[1])
+        {
+            let result = self.call_function(&call[1], &call[2], context, state)?;
+            // `git().commit.hash` picks a field out of the returned value
+            return Ok(match call.get(3) {
+                Some(path) => resolve_path(&result, path.as_str()).cloned(),
+                None => Some(result),
+            });
+        }
+        if let Some((cond, then, otherwise)) = expr::split_ternary(expression) {
+            let branch = if expr::evaluate_condition(cond, context)? {
+                then
             } else {
-                VarUsage::CollectionOfObjects(structure)
+                otherwise
+            };
+            return match serde_json::from_str(branch) {
+                Ok(literal) => Ok(Some(literal)),
+                Err(_) => self.evaluate_output(branch, context, state),
             };
-            variables.insert(base_var.to_string(), usage);
+        }
+        expr::evaluate_arithmetic(expression, context).map(Some)
+    }
+
+    /// Adds a function callable as `name(arg: value, ...)`, replacing any
+    /// function with the same name, built-in ones included.
+    pub fn register_function(&mut self, name: &'static str, function: BuiltInFn) {
+        self.functions.insert(name, function);
+    }
+
+    /// Adds a filter usable as `{{ value | name }}`, replacing any filter with
+    /// the same name. It accepts any number of arguments; use
+    /// `filters.register` to declare its arity and description instead.
+    pub fn register_filter(&mut self, name: &'static str, filter: FilterFn) {
+        self.filters.register(Filter {
+            name,
+            arity: 0,
+            optional_args: 0,
+            variadic: true,
+            signature: name,
+            description: "",
+            apply: filter,
+        });
+    }
+
+    /// Makes the functions and filters of a plugin callable from templates.
+    /// Built-in functions and filters keep precedence over plugin ones.
+    pub fn add_plugin(&mut self, plugin: Box<dyn Plugin>) {
+        let plugin: Arc<dyn Plugin> = Arc::from(plugin);
+        for name in plugin.functions() {
+            self.plugin_functions
+                .insert(name.clone(), Arc::clone(&plugin));
+        }
+        for name in plugin.filters() {
+            self.filters.register_plugin(name, Arc::clone(&plugin));
         }
     }
 
-    for caps in RE_VAR.captures_iter(template) {
-        if let Some(base_var) = caps[1].split('.').next() {
-            if !RESERVED_WORDS.contains(base_var) && !all_loop_vars.contains(base_var) {
+    fn has_function(&self, name: &str) -> bool {
+        self.functions.contains_key(name)
+            || self.plugin_functions.contains_key(name)
+            || self.external_functions.contains_key(name)
+    }
+
+    /// Fails with the first capability the built-in function `name` needs
+    /// for this call that hasn't been granted.
+    fn check_capabilities(&self, name: &str, args: &Map<String, Value>) -> Result<(), String> {
+        match capability::required_by(name, args)
+            .into_iter()
+            .find(|capability| !self.capabilities.contains(capability))
+        {
+            Some(missing) => Err(format!(
+                "Function '{}' needs the '{}' capability, pass --allow {} to grant it",
+                name, missing, missing
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails unless `exec` is granted, for `what` (an external function or a
+    /// command filter) running a shell command from `config.toml`.
+    fn require_exec(&self, what: &str) -> Result<(), String> {
+        if self.capabilities.contains(&Capability::Exec) {
+            return Ok(());
+        }
+        Err(format!(
+            "{} runs a shell command and needs the 'exec' capability, pass --allow exec to grant it",
+            what
+        ))
+    }
+
+    /// Applies a filter to `value`. In a dry run, command and plugin filters
+    /// leave it unchanged, as they may have side effects.
+    fn apply_filter(
+        &self,
+        name: &str,
+        value: &Value,
+        args: &[Value],
+        dry_run: bool,
+    ) -> Result<Value, String> {
+        if dry_run && self.filters.is_external(name) {
+            return Ok(value.clone());
+        }
+        if self.filters.is_command(name) {
+            self.require_exec(&format!("Filter '{}'", name))?;
+        }
+        self.filters.apply(name, value, args)
+    }
+
+    /// Calls a built-in, plugin or external function with `name: value` arguments.
+    /// A dry run only records the call and evaluates it to `null`.
+    fn call_function(
+        &self,
+        name: &str,
+        args_str: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<Value, String> {
+        if state.dry_run {
+            if !self.has_function(name) {
+                return Err(format!("Unknown function '{}'", name));
+            }
+            let args = parse_function_args(args_str, context)?;
+            let required = if self.functions.contains_key(name) {
+                capability::required_by(name, &args)
+            } else if self.plugin_functions.contains_key(name) {
+                Vec::new()
+            } else {
+                vec![Capability::Exec]
+            };
+            let missing_capabilities = required
+                .into_iter()
+                .filter(|capability| !self.capabilities.contains(capability))
+                .collect();
+            let call = PlannedCall {
+                name: name.to_string(),
+                args,
+                missing_capabilities,
+            };
+            if !state.calls.contains(&call) {
+                state.calls.push(call);
+            }
+            return Ok(Value::Null);
+        }
+        if !self.functions.contains_key(name)
+            && let Some(plugin) = self.plugin_functions.get(name)
+        {
+            let args_map = parse_function_args(args_str, context)?;
+            return plugin
+                .call(name, &args_map)
+                .map_err(|e| format!("Error in function '{}': {}", name, value_to_string(&e)));
+        }
+        let Some(func) = self.functions.get(name) else {
+            let cmd = self
+                .external_functions
+                .get(name)
+                .ok_or_else(|| format!("Unknown function '{}'", name))?;
+            self.require_exec(&format!("Function '{}'", name))?;
+            let args_map = parse_function_args(args_str, context)?;
+            return builtin_fns::run_external_function(cmd, &args_map)
+                .map_err(|e| format!("Error in function '{}': {}", name, value_to_string(&e)));
+        };
+        let args_map = parse_function_args(args_str, context)?;
+        self.check_capabilities(name, &args_map)?;
+
+        func(&args_map)
+            .map_err(|e| format!("Error in function '{}': {}", name, value_to_string(&e)))
+    }
+
+    /// Calls `files(...)` for a loop whose body never reads file contents,
+    /// so the files are listed without being read.
+    fn call_files_without_content(&self, args_str: &str, context: &Value) -> Result<Value, String> {
+        let mut args_map = parse_function_args(args_str, context)?;
+        self.check_capabilities("files", &args_map)?;
+        args_map.entry("content").or_insert(Value::Bool(false));
+        builtin_fns::files(&args_map)
+            .map_err(|e| format!("Error in function 'files': {}", value_to_string(&e)))
+    }
+
+    pub fn extract_variables(&self, template: &str) -> HashMap<String, VarUsage> {
+        self.extract_variables_at_depth(template, 0)
+    }
+
+    fn extract_variables_at_depth(
+        &self,
+        template: &str,
+        depth: usize,
+    ) -> HashMap<String, VarUsage> {
+        let (template, _) = extract_raw_blocks(template);
+        let template = &*strip_comments(&template);
+        let mut variables = HashMap::new();
+
+        let all_loop_vars: HashSet<String> = RE_FOREACH
+            .captures_iter(template)
+            .flat_map(|caps| {
+                caps[2]
+                    .split(',')
+                    .map(|name| name.trim().to_string())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        for caps in RE_FOREACH.captures_iter(template) {
+            let source_path = &caps[3];
+            if let Some(base_var) = path_segments(source_path).next() {
+                if all_loop_vars.contains(base_var) {
+                    continue;
+                }
+
+                let is_function_call = caps.get(4).is_some();
+                if is_function_call && self.filters.get(source_path).is_some() {
+                    // Collections passed to `zip(a, b)` and the like
+                    for arg in split_args(&caps[4]) {
+                        if is_plain_path(&arg)
+                            && let Some(arg_base) = path_segments(&arg).next()
+                            && !all_loop_vars.contains(arg_base)
+                        {
+                            variables
+                                .entry(arg_base.to_string())
+                                .or_insert(VarUsage::CollectionOfSimple);
+                        }
+                    }
+                    continue;
+                }
+                if is_function_call || self.has_function(source_path) {
+                    continue;
+                }
+
+                let item_var = item_var_name(&caps[2]);
+                let loop_body = find_loop_body(template, caps.get(0).unwrap().as_str());
+
+                let structure = analyze_object_structure(&loop_body, item_var);
+
+                let usage = if structure.is_empty() {
+                    VarUsage::CollectionOfSimple
+                } else {
+                    VarUsage::CollectionOfObjects(structure)
+                };
+                variables.insert(base_var.to_string(), usage);
+            }
+        }
+
+        let condition_paths = blocks::scan(template)
+            .into_iter()
+            .filter(|tag| matches!(tag.keyword, "if" | "elif" | "unless" | "switch" | "with"))
+            .flat_map(|tag| expr::condition_paths(tag.args));
+        let tag_paths = RE_VAR
+            .captures_iter(template)
+            .map(|caps| caps.get(1).unwrap().as_str())
+            // Function calls like `git().branch` don't read the context
+            .filter(|expression| !RE_FUNCTION_CALL.is_match(expression.trim()))
+            .flat_map(expr::expression_paths);
+        let call_arg_paths = RE_DIRECTIVE
+            .captures_iter(template)
+            .filter(|caps| &caps[2] == "call")
+            .flat_map(|caps| {
+                RE_CALL_ARG_PATH
+                    .captures_iter(caps.get(3).unwrap().as_str())
+                    .map(|arg| arg.get(1).unwrap().as_str())
+                    .filter(|path| !matches!(*path, "true" | "false" | "null"))
+                    .collect::<Vec<_>>()
+            });
+        // Names defined by the template itself: macro parameters, captures and script results
+        let mut local_names: HashSet<String> = blocks::scan(template)
+            .into_iter()
+            .filter(|tag| tag.keyword == "macro")
+            .filter_map(|tag| parse_macro_signature(tag.args).ok())
+            .flat_map(|(_, params)| params)
+            .collect();
+        local_names.extend(
+            blocks::scan(template)
+                .into_iter()
+                .filter(|tag| matches!(tag.keyword, "capture" | "script"))
+                .filter(|tag| !tag.args.is_empty())
+                .map(|tag| tag.args.to_string()),
+        );
+
+        for path in tag_paths.chain(condition_paths).chain(call_arg_paths) {
+            if let Some(base_var) = path_segments(path).next()
+                && !RESERVED_WORDS.contains(base_var)
+                && !all_loop_vars.contains(base_var)
+                && !local_names.contains(base_var)
+            {
                 variables
                     .entry(base_var.to_string())
                     .or_insert(VarUsage::Simple);
             }
         }
-    }
 
-    variables
-}
+        // Included templates are best-effort: unreadable ones simply add nothing
+        if depth < MAX_NESTING_DEPTH {
+            for caps in RE_DIRECTIVE.captures_iter(template) {
+                if &caps[2] != "include" {
+                    continue;
+                }
+                if let Ok(content) = parse_quoted_name("include", &caps[3])
+                    .and_then(|name| read_included_template(&name))
+                {
+                    for (var, usage) in self.extract_variables_at_depth(&content, depth + 1) {
+                        variables.entry(var).or_insert(usage);
+                    }
+                }
+            }
+        }
 
-pub fn render(template: &str, context: &Context) -> Result<String, String> {
-    let context_value = Value::Object(context.0.clone().into_iter().collect());
-    render_recursive(template, &context_value)
-}
+        variables
+    }
 
-fn render_recursive(template: &str, context: &Value) -> Result<String, String> {
-    if let Some(start_match) = RE_FOREACH.find(template) {
-        let search_start_pos = start_match.end();
-        let mut nesting_level = 0;
-        let mut end_match_pos = None;
+    fn render_recursive(
+        &self,
+        template: &str,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let Some(block) = blocks::find_first_block(template)? else {
+            return self.render_variables(template, context, state);
+        };
 
-        for (offset, tag_type) in RE_FOREACH
-            .find_iter(&template[search_start_pos..])
-            .map(|m| (m.start(), "start"))
-            .chain(
-                RE_ENDFOR
-                    .find_iter(&template[search_start_pos..])
-                    .map(|m| (m.start(), "end")),
-            )
-            .sorted_by_key(|(offset, _)| *offset)
-        {
-            if tag_type == "start" {
-                nesting_level += 1;
-            } else if nesting_level == 0 {
-                end_match_pos = Some(search_start_pos + offset);
-                break;
-            } else {
-                nesting_level -= 1;
+        let rendered_before = self.render_variables(&template[..block.start], context, state)?;
+        if state.flow.is_some() {
+            return Ok(rendered_before);
+        }
+        let binding = match block.keyword() {
+            "capture" => {
+                let (name, captured) = self.render_capture(&block, context, state)?;
+                Some((name, Value::String(captured)))
+            }
+            "script" if !block.sections[0].tag.args.is_empty() => {
+                let (name, result) = self.evaluate_script(&block, context)?;
+                Some((name, result))
+            }
+            _ => None,
+        };
+        if let Some((name, value)) = binding {
+            // The captured text or script result is only visible to the rest of the template
+            let mut scoped = context.as_object().cloned().unwrap_or_default();
+            scoped.insert(name, value);
+            let rendered_after =
+                self.render_recursive(&template[block.end..], &Value::Object(scoped), state)?;
+            return Ok(format!("{}{}", rendered_before, rendered_after));
+        }
+        let rendered_block = match block.keyword() {
+            "foreach" => self.render_foreach(&block, context, state)?,
+            "if" | "unless" => self.render_if(&block, context, state)?,
+            "switch" => self.render_switch(&block, context, state)?,
+            "with" => self.render_with(&block, context, state)?,
+            "sep" => self.render_sep(&block, context, state)?,
+            "script" => match self.evaluate_script(&block, context)?.1 {
+                Value::Null => String::new(),
+                result => value_to_string(&result),
+            },
+            "macro" => {
+                return Err("Macros must be defined at the top level of a template".to_string());
             }
+            other => unreachable!("unhandled block '{}'", other),
+        };
+        if state.flow.is_some() {
+            return Ok(format!("{}{}", rendered_before, rendered_block));
         }
+        let rendered_after = self.render_recursive(&template[block.end..], context, state)?;
 
-        if let Some(end_pos) = end_match_pos {
-            let end_match = RE_ENDFOR.find_at(template, end_pos).unwrap();
+        Ok(format!(
+            "{}{}{}",
+            rendered_before, rendered_block, rendered_after
+        ))
+    }
 
-            let before_loop = &template[..start_match.start()];
-            let loop_body_template = &template[start_match.end()..end_match.start()];
-            let after_loop = &template[end_match.end()..];
+    /// Renders a `{{sep}}` block: its content goes between the items of the
+    /// enclosing loop, so it is skipped on the last item.
+    fn render_sep(
+        &self,
+        block: &Block,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let head = &block.sections[0];
+        if let Some(extra) = block.sections.get(1) {
+            return Err(format!(
+                "Unexpected '{{{{{}}}}}' inside a sep block",
+                extra.tag.keyword
+            ));
+        }
+        if state.loop_depth == 0 {
+            return Err("'{{sep}}' can only be used inside a foreach loop".to_string());
+        }
+        match resolve_path(context, "loop.last") {
+            Some(Value::Bool(true)) => Ok(String::new()),
+            _ => self.render_recursive(head.body, context, state),
+        }
+    }
 
-            let rendered_before = render_recursive(before_loop, context)?;
+    /// Renders a `{{capture name}}` block, returning the name and the text.
+    fn render_capture(
+        &self,
+        block: &Block,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<(String, String), String> {
+        let head = &block.sections[0];
+        if let Some(extra) = block.sections.get(1) {
+            return Err(format!(
+                "Unexpected '{{{{{}}}}}' inside a capture block",
+                extra.tag.keyword
+            ));
+        }
+        if !RE_IDENTIFIER.is_match(head.tag.args) {
+            return Err(format!(
+                "'{{{{capture {}}}}}' needs a variable name",
+                head.tag.args
+            ));
+        }
+        let captured = self.render_recursive(head.body, context, state)?;
+        Ok((head.tag.args.to_string(), captured))
+    }
 
-            let caps = RE_FOREACH.captures(start_match.as_str()).unwrap();
-            let item_name = &caps[2];
-            let source_name = &caps[3];
-            let args_str_opt = caps.get(4).map(|m| m.as_str());
+    /// Evaluates a `{{script}}` or `{{script name}}` block, returning the
+    /// name (empty without one) and the value of the script.
+    fn evaluate_script(&self, block: &Block, context: &Value) -> Result<(String, Value), String> {
+        let head = &block.sections[0];
+        if let Some(extra) = block.sections.get(1) {
+            return Err(format!(
+                "Unexpected '{{{{{}}}}}' inside a script block",
+                extra.tag.keyword
+            ));
+        }
+        if !head.tag.args.is_empty() && !RE_IDENTIFIER.is_match(head.tag.args) {
+            return Err(format!(
+                "'{{{{script {}}}}}' needs a variable name",
+                head.tag.args
+            ));
+        }
+        let result = script::evaluate(head.body, context)?;
+        Ok((head.tag.args.to_string(), result))
+    }
 
-            let collection_val = if let Some(args_str) = args_str_opt {
-                let func = BUILTIN_FNS
-                    .get(source_name)
-                    .ok_or_else(|| format!("Unknown function '{}'", source_name))?;
+    fn render_foreach(
+        &self,
+        block: &Block,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        // `{{else}}` is the only divider a loop takes, rendered when there is nothing to iterate
+        let else_body = match &block.sections[1..] {
+            [] => None,
+            [section] if section.tag.keyword == "else" && section.tag.args.is_empty() => {
+                Some(section.body)
+            }
+            [.., extra] => {
+                return Err(format!(
+                    "Unexpected '{{{{{}}}}}' inside a foreach loop",
+                    extra.tag.keyword
+                ));
+            }
+        };
 
-                let args_map = parse_function_args(args_str, context)?;
+        let head = &block.sections[0];
+        let caps = RE_FOREACH_HEAD
+            .captures(head.tag.args)
+            .ok_or_else(|| format!("Invalid foreach syntax: '{}'", head.tag.args))?;
+        let (key_name, item_name) = match caps.get(2) {
+            Some(value_name) => (Some(&caps[1]), value_name.as_str()),
+            None => (None, &caps[1]),
+        };
+        let mut pipeline = split_pipeline(&caps[3]).into_iter();
+        let source = pipeline.next().unwrap_or_default();
+        let source_caps = RE_FOREACH_SOURCE
+            .captures(source)
+            .ok_or_else(|| format!("Invalid foreach syntax: '{}'", head.tag.args))?;
+        let source_name = source_caps.get(1).unwrap().as_str();
+        let args_str_opt = source_caps.get(2).map(|m| m.as_str());
 
-                func(&args_map).map_err(|e| {
-                    format!(
-                        "Error in function '{}': {}",
-                        source_name,
-                        value_to_string(&e)
-                    )
-                })?
+        let collection_val = if let Some(args_str) = args_str_opt
+            && !self.has_function(source_name)
+            && self.filters.get(source_name).is_some()
+        {
+            // `zip(a, b)` is shorthand for `a | zip(b)`
+            let mut args = split_args(args_str)
+                .iter()
+                .map(|arg| resolve_arg_value(arg, context))
+                .collect::<Result<Vec<_>, _>>()?;
+            if args.is_empty() {
+                return Err(format!("'{}' needs a collection to work on", source_name));
+            }
+            let input = args.remove(0);
+            self.filters.apply(source_name, &input, &args)?
+        } else if let Some(args_str) = args_str_opt {
+            let result = if source_name == "files"
+                && !state.dry_run
+                && source_caps.get(3).is_none()
+                && pipeline.len() == 0
+                && !reads_file_content(item_name, head.body)
+            {
+                self.call_files_without_content(args_str, context)?
             } else {
-                resolve_path(context, source_name)
-                    .cloned()
-                    .unwrap_or(Value::Array(vec![]))
+                self.call_function(source_name, args_str, context, state)?
             };
+            match source_caps.get(3) {
+                Some(path) => resolve_path(&result, path.as_str())
+                    .cloned()
+                    .unwrap_or(Value::Array(vec![])),
+                None => result,
+            }
+        } else {
+            resolve_path(context, source_name)
+                .cloned()
+                .unwrap_or(Value::Array(vec![]))
+        };
 
-            let mut rendered_loop_body = String::new();
-            let items_to_iterate = match collection_val {
-                Value::Array(arr) => arr,
+        let dry_run = state.dry_run;
+        let collection_val = pipeline.try_fold(collection_val, |value, filter| {
+            let (name, args) = parse_filter(filter, context)?;
+            self.apply_filter(name, &value, &args, dry_run)
+        })?;
 
-                single_val => vec![single_val],
-            };
+        let mut rendered_loop_body = String::new();
+        // Pairs of (key, item): object entries with `key, value`, otherwise array indices
+        let items_to_iterate: Vec<(Value, Value)> = match collection_val {
+            Value::Object(map) if key_name.is_some() => map
+                .into_iter()
+                .map(|(key, value)| (Value::String(key), value))
+                .collect(),
+            Value::Array(arr) => arr
+                .into_iter()
+                .enumerate()
+                .map(|(index, item)| (Value::from(index), item))
+                .collect(),
+
+            Value::Null => vec![],
+            single_val => vec![(Value::from(0), single_val)],
+        };
+
+        if items_to_iterate.is_empty()
+            && let Some(else_body) = else_body
+        {
+            return self.render_recursive(else_body, context, state);
+        }
 
-            for item in items_to_iterate {
-                if let Some(mut new_context_obj) = context.as_object().cloned() {
-                    new_context_obj.insert(item_name.to_string(), item.clone());
-                    let new_context_val = Value::Object(new_context_obj);
-                    rendered_loop_body
-                        .push_str(&render_recursive(loop_body_template, &new_context_val)?);
+        let length = items_to_iterate.len();
+        state.loop_depth += 1;
+        for (index, (key, item)) in items_to_iterate.into_iter().enumerate() {
+            if let Some(mut new_context_obj) = context.as_object().cloned() {
+                if let Some(key_name) = key_name {
+                    new_context_obj.insert(key_name.to_string(), key);
+                }
+                new_context_obj.insert(item_name.to_string(), item);
+                new_context_obj.insert(
+                    "loop".to_string(),
+                    json!({
+                        "index": index,
+                        "first": index == 0,
+                        "last": index + 1 == length,
+                        "length": length,
+                    }),
+                );
+                let new_context_val = Value::Object(new_context_obj);
+                rendered_loop_body.push_str(&self.render_recursive(
+                    head.body,
+                    &new_context_val,
+                    state,
+                )?);
+                if state.flow.take() == Some(LoopFlow::Break) {
+                    break;
                 }
             }
+        }
+        state.loop_depth -= 1;
 
-            let rendered_after = render_recursive(after_loop, context)?;
+        Ok(rendered_loop_body)
+    }
+
+    /// Renders the first `{{if}}`/`{{elif}}` section whose condition holds,
+    /// or the `{{else}}` section if none does. `{{unless}}` is an `{{if}}`
+    /// with the condition negated.
+    fn render_if(
+        &self,
+        block: &Block,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let last = block.sections.len() - 1;
+        for (i, section) in block.sections.iter().enumerate() {
+            let matched = match section.tag.keyword {
+                keyword @ ("if" | "elif" | "unless") => {
+                    let holds =
+                        expr::evaluate_condition(section.tag.args, context).map_err(|e| {
+                            format!("{} in '{{{{{} {}}}}}'", e, keyword, section.tag.args)
+                        })?;
+                    holds != (keyword == "unless")
+                }
+                "else" if i == last && section.tag.args.is_empty() => true,
+                "else" if i != last => {
+                    return Err("'{{else}}' must be the last section of an if block".to_string());
+                }
+                other => {
+                    return Err(format!(
+                        "Unexpected '{{{{{} {}}}}}' inside an if block",
+                        other, section.tag.args
+                    ));
+                }
+            };
+            if matched {
+                return self.render_recursive(section.body, context, state);
+            }
+        }
+        Ok(String::new())
+    }
 
-            return Ok(format!(
-                "{}{}{}",
-                rendered_before, rendered_loop_body, rendered_after
+    /// Renders the first `{{case}}` listing a value equal to the `{{switch}}`
+    /// operand, or the `{{else}}` section if none does.
+    fn render_switch(
+        &self,
+        block: &Block,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let head = &block.sections[0];
+        if !head.body.trim().is_empty() {
+            return Err(format!(
+                "Unexpected text before the first '{{{{case}}}}' of '{{{{switch {}}}}}'",
+                head.tag.args
             ));
         }
+        let value = expr::resolve_operand(head.tag.args, context).unwrap_or(Value::Null);
+
+        let last = block.sections.len() - 1;
+        for (i, section) in block.sections.iter().enumerate().skip(1) {
+            let matched = match section.tag.keyword {
+                "case" if section.tag.args.is_empty() => {
+                    return Err("'{{case}}' needs at least one value".to_string());
+                }
+                "case" => split_args(section.tag.args).iter().any(|candidate| {
+                    let candidate =
+                        expr::resolve_operand(candidate, context).unwrap_or(Value::Null);
+                    expr::compare_with(&value, "==", &candidate) == Some(true)
+                }),
+                "else" if i == last && section.tag.args.is_empty() => true,
+                "else" if i != last => {
+                    return Err("'{{else}}' must be the last section of a switch block".to_string());
+                }
+                other => {
+                    return Err(format!(
+                        "Unexpected '{{{{{} {}}}}}' inside a switch block",
+                        other, section.tag.args
+                    ));
+                }
+            };
+            if matched {
+                return self.render_recursive(section.body, context, state);
+            }
+        }
+        Ok(String::new())
+    }
+
+    /// Renders the body with the fields of an object added to the context, so
+    /// `{{with server.production}}{{ host }}{{endwith}}` reads `server.production.host`.
+    /// A missing or `null` object renders the `{{else}}` section instead, if any.
+    fn render_with(
+        &self,
+        block: &Block,
+        context: &Value,
+        state: &mut RenderState,
+    ) -> Result<String, String> {
+        let head = &block.sections[0];
+        let else_body = match &block.sections[1..] {
+            [] => None,
+            [section] if section.tag.keyword == "else" && section.tag.args.is_empty() => {
+                Some(section.body)
+            }
+            [.., extra] => {
+                return Err(format!(
+                    "Unexpected '{{{{{}}}}}' inside a with block",
+                    extra.tag.keyword
+                ));
+            }
+        };
+
+        match resolve_path(context, head.tag.args) {
+            Some(Value::Object(scope)) => {
+                let mut scoped = context.as_object().cloned().unwrap_or_default();
+                scoped.extend(scope.clone());
+                self.render_recursive(head.body, &Value::Object(scoped), state)
+            }
+            None | Some(Value::Null) => match else_body {
+                Some(body) => self.render_recursive(body, context, state),
+                None => Ok(String::new()),
+            },
+            Some(_) => Err(format!("'{{{{with {}}}}}' needs an object", head.tag.args)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(json: Value) -> Context {
+        Context::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn unknown_function_is_kept_in_lenient_mode() {
+        let engine = Engine::default();
+        let rendered = engine.render("a {{ noww() }}", &Context::default());
+        assert_eq!(rendered.unwrap(), "a {{ noww() }}");
+    }
+
+    #[test]
+    fn unknown_function_fails_in_strict_mode() {
+        let engine = Engine {
+            strict_functions: true,
+            ..Engine::default()
+        };
+        let err = engine
+            .render("a {{ noww() }}", &context(json!({})))
+            .unwrap_err();
+        assert_eq!(err, "Unknown function 'noww'");
+    }
+
+    #[test]
+    fn registered_filter_takes_any_number_of_args() {
+        fn count_args(_value: &Value, args: &[Value]) -> Result<Value, String> {
+            Ok(Value::from(args.len()))
+        }
+        let mut engine = Engine::default();
+        engine.register_filter("count_args", count_args);
+
+        let rendered = engine.render(
+            "{{ x | count_args }} {{ x | count_args(1, 2, 3) }}",
+            &Context::default(),
+        );
+        assert_eq!(rendered.unwrap(), "0 3");
+    }
+
+    #[test]
+    fn builtin_filter_rejects_extra_args() {
+        let err = Engine::default()
+            .render("{{ x | upper(1) }}", &context(json!({ "x": "a" })))
+            .unwrap_err();
+        assert_eq!(err, "Filter 'upper' expects 0 argument(s), got 1");
+    }
+
+    #[test]
+    fn quoted_filter_args_may_contain_pipes_and_braces() {
+        let rendered = Engine::default().render(
+            r#"{{ s | regex_replace("a|b", "X") }} {{ missing | default("}") }}"#,
+            &context(json!({ "s": "abc" })),
+        );
+        assert_eq!(rendered.unwrap(), "XXc }");
+    }
+
+    #[test]
+    fn merge_filter_takes_its_overlay_from_the_context() {
+        let rendered = Engine::default().render(
+            r#"{{ defaults | merge(overrides) | json }} {{ s | replace("a", "|") }}"#,
+            &context(json!({
+                "defaults": { "a": { "b": 1, "c": 2 } },
+                "overrides": { "a": { "c": 3 } },
+                "s": "abc"
+            })),
+        );
+        assert_eq!(rendered.unwrap(), r#"{"a":{"b":1,"c":3}} |bc"#);
+    }
+
+    #[test]
+    fn macros_called_in_a_files_loop_see_the_content() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let mut engine = Engine::default();
+        engine.capabilities.insert(Capability::Fs);
+        let template = format!(
+            "{{{{macro show()}}}}[{{{{ file.content }}}}]{{{{endmacro}}}}\
+             {{{{foreach file in files(source: {:?})}}}}{{{{call show()}}}}{{{{endfor}}}}",
+            dir.path().display().to_string()
+        );
+        assert_eq!(
+            engine.render(&template, &Context::default()).unwrap(),
+            "[hello]"
+        );
+    }
+
+    #[test]
+    fn file_content_use_is_detected_in_loop_bodies() {
+        assert!(!reads_file_content(
+            "file",
+            "{{ file.path }} {{ file.name }}"
+        ));
+        assert!(!reads_file_content("file", "{{ profile.content }}"));
+        assert!(reads_file_content("file", "{{ file.content }}"));
+        assert!(reads_file_content("file", "{{ file | json }}"));
+        assert!(reads_file_content(
+            "file",
+            "{{ file.path }}{{include other}}"
+        ));
+    }
+
+    #[test]
+    fn sep_with_spaces_is_a_variable() {
+        let rendered = Engine::default().render(
+            "x={{ sep }} {{foreach n in list}}{{ n }}{{sep}},{{endsep}}{{endfor}}",
+            &context(json!({ "sep": "V", "list": [1, 2] })),
+        );
+        assert_eq!(rendered.unwrap(), "x=V 1,2");
+    }
+
+    #[test]
+    fn with_without_an_argument_is_a_variable() {
+        let rendered = Engine::default().render(
+            "{{ with }} {{with server}}{{ host }}{{endwith}}",
+            &context(json!({ "with": "W", "server": { "host": "h" } })),
+        );
+        assert_eq!(rendered.unwrap(), "W h");
+    }
+
+    #[test]
+    fn capture_without_a_name_is_a_variable() {
+        let rendered = Engine::default().render(
+            "{{capture title}}T{{endcapture}}{{ capture }} {{ title }}",
+            &context(json!({ "capture": "C" })),
+        );
+        assert_eq!(rendered.unwrap(), "C T");
+    }
+
+    #[test]
+    fn script_with_spaces_is_a_variable() {
+        let rendered = Engine::default().render(
+            "{{ script }} {{ script | upper }} {{script}}1 + 1{{endscript}}",
+            &context(json!({ "script": "build" })),
+        );
+        assert_eq!(rendered.unwrap(), "build BUILD 2");
+    }
+
+    #[test]
+    fn loop_index_picks_aligned_elements_of_another_array() {
+        let rendered = Engine::default().render(
+            "{{foreach name in names}}{{ name }} <{{ emails | get:loop.index }}>;{{endfor}}",
+            &context(json!({
+                "names": ["Ann", "Bob"],
+                "emails": ["ann@example.com", "bob@example.com"]
+            })),
+        );
+        assert_eq!(
+            rendered.unwrap(),
+            "Ann <ann@example.com>;Bob <bob@example.com>;"
+        );
+    }
+
+    fn strict() -> Engine {
+        Engine {
+            strict_variables: true,
+            ..Engine::default()
+        }
     }
 
-    Ok(render_variables(template, context))
+    #[test]
+    fn undefined_variable_fails_in_strict_mode() {
+        let err = strict()
+            .render("Hi {{ name }}", &context(json!({})))
+            .unwrap_err();
+        assert_eq!(err, "Undefined variable 'name' at line 1, column 4");
+    }
+
+    #[test]
+    fn strict_mode_lists_every_undefined_variable() {
+        let err = strict()
+            .render("{{ a }}\n{{ b }}", &context(json!({})))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Undefined variables: 'a' at line 1, column 1; 'b' at line 2, column 1"
+        );
+    }
+
+    #[test]
+    fn default_filter_satisfies_strict_mode() {
+        let rendered = strict().render(
+            "Hi {{ name | default(\"anonymous\") }}",
+            &context(json!({})),
+        );
+        assert_eq!(rendered.unwrap(), "Hi anonymous");
+    }
+
+    #[test]
+    fn function_fails_without_its_capability() {
+        let err = Engine::default()
+            .render("{{ env(name: \"HOME\") }}", &Context::default())
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Function 'env' needs the 'env' capability, pass --allow env to grant it \
+             in '{{ env(name: \"HOME\") }}'"
+        );
+    }
+
+    #[test]
+    fn commands_from_the_config_need_exec() {
+        let mut engine = Engine::default();
+        engine.filters.register_command("shout", "tr a-z A-Z");
+        engine
+            .external_functions
+            .insert("hello".to_string(), "echo '\"hi\"'".to_string());
+
+        let err = engine
+            .render("{{ s | shout }}", &context(json!({ "s": "a" })))
+            .unwrap_err();
+        assert_eq!(
+            err,
+            "Filter 'shout' runs a shell command and needs the 'exec' capability, \
+             pass --allow exec to grant it"
+        );
+        let err = engine
+            .render("{{ hello() }}", &Context::default())
+            .unwrap_err();
+        assert!(
+            err.starts_with("Function 'hello' runs a shell command"),
+            "{}",
+            err
+        );
+
+        engine.capabilities.insert(Capability::Exec);
+        let rendered = engine.render(
+            "{{ s | shout }} {{ hello() }}",
+            &context(json!({ "s": "a" })),
+        );
+        assert_eq!(rendered.unwrap(), "A hi");
+    }
+
+    #[test]
+    fn granted_capability_allows_the_call() {
+        let mut engine = Engine::default();
+        engine.capabilities.insert(Capability::Env);
+        let rendered = engine.render(
+            "{{ env(name: \"RUNTPL_TEST_UNSET\", default: \"none\") }}",
+            &Context::default(),
+        );
+        assert_eq!(rendered.unwrap(), "none");
+    }
 }
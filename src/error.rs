@@ -1,4 +1,3 @@
-use serde_json;
 use std::io;
 
 #[derive(Debug)]
@@ -8,6 +7,9 @@ pub enum AppError {
     Editor(String),
     JsonParse(String),
     InteractiveAbort(String),
+    Render(String),
+    Config(String),
+    Plugin(String),
 }
 
 impl From<io::Error> for AppError {
@@ -16,6 +18,14 @@ impl From<io::Error> for AppError {
     }
 }
 
+impl From<dialoguer::Error> for AppError {
+    fn from(err: dialoguer::Error) -> Self {
+        match err {
+            dialoguer::Error::IO(err) => AppError::Io(err),
+        }
+    }
+}
+
 impl From<serde_json::Error> for AppError {
     fn from(err: serde_json::Error) -> Self {
         AppError::JsonParse(err.to_string())
@@ -30,6 +40,9 @@ impl std::fmt::Display for AppError {
             AppError::Editor(msg) => write!(f, "Editor Error: {}", msg),
             AppError::JsonParse(msg) => write!(f, "JSON Parse Error: {}", msg),
             AppError::InteractiveAbort(msg) => write!(f, "{}", msg),
+            AppError::Render(msg) => write!(f, "Error rendering template: {}", msg),
+            AppError::Config(msg) => write!(f, "Config Error: {}", msg),
+            AppError::Plugin(msg) => write!(f, "Plugin Error: {}", msg),
         }
     }
 }
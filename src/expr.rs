@@ -0,0 +1,332 @@
+use crate::engine::resolve_path;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+const OPERATORS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+/// Missing values, `null`, `false`, `0`, empty strings, arrays and objects are falsy.
+pub fn is_truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(arr)) => !arr.is_empty(),
+        Some(Value::Object(map)) => !map.is_empty(),
+    }
+}
+
+/// Splits `lhs OP rhs` at the first comparison operator outside of quotes.
+fn split_comparison(cond: &str) -> Option<(&str, &str, &str)> {
+    let mut in_quotes = false;
+    for (i, c) in cond.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            _ if in_quotes => {}
+            _ => {
+                if let Some(op) = OPERATORS.iter().find(|op| cond[i..].starts_with(*op)) {
+                    return Some((cond[..i].trim(), op, cond[i + op.len()..].trim()));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// An operand is either a JSON literal (`"prod"`, `3`, `true`) or a context path.
+pub(crate) fn resolve_operand(operand: &str, context: &Value) -> Option<Value> {
+    serde_json::from_str(operand)
+        .ok()
+        .or_else(|| resolve_path(context, operand).cloned())
+}
+
+fn is_literal(operand: &str) -> bool {
+    serde_json::from_str::<Value>(operand).is_ok()
+}
+
+fn as_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => parse_number(s.trim()),
+        _ => None,
+    }
+}
+
+/// Parses decimal notation only: a digit first, optionally after a sign or a
+/// dot. Words `f64` would also take, such as `inf` or `nan`, are not numbers.
+fn parse_number(text: &str) -> Option<f64> {
+    let digits = text.strip_prefix(['-', '+']).unwrap_or(text);
+    let digits = digits.strip_prefix('.').unwrap_or(digits);
+    if !digits.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse().ok()
+}
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Numbers (including numeric strings, as CLI arguments are always strings)
+/// compare numerically, everything else compares as text.
+pub(crate) fn compare(lhs: &Value, rhs: &Value) -> Option<Ordering> {
+    match (as_number(lhs), as_number(rhs)) {
+        (Some(l), Some(r)) => l.partial_cmp(&r),
+        _ => Some(as_text(lhs).cmp(&as_text(rhs))),
+    }
+}
+
+/// Evaluates an `{{if}}`/`{{elif}}` condition: either a single operand tested
+/// for truthiness, or a comparison `lhs OP rhs` with `==`, `!=`, `<`, `>`, `<=`, `>=`.
+pub fn evaluate_condition(cond: &str, context: &Value) -> Result<bool, String> {
+    let cond = cond.trim();
+    if cond.is_empty() {
+        return Err("Condition is empty".to_string());
+    }
+
+    let Some((lhs, op, rhs)) = split_comparison(cond) else {
+        return Ok(is_truthy(resolve_operand(cond, context).as_ref()));
+    };
+    if lhs.is_empty() || rhs.is_empty() {
+        return Err(format!("Incomplete comparison '{}'", cond));
+    }
+
+    let lhs = resolve_operand(lhs, context).unwrap_or(Value::Null);
+    let rhs = resolve_operand(rhs, context).unwrap_or(Value::Null);
+    Ok(compare_with(&lhs, op, &rhs).unwrap())
+}
+
+/// Applies a comparison operator; `None` if `op` is not one of `OPERATORS`.
+pub(crate) fn compare_with(lhs: &Value, op: &str, rhs: &Value) -> Option<bool> {
+    let ordering = compare(lhs, rhs);
+    Some(match op {
+        "==" => ordering == Some(Ordering::Equal),
+        "!=" => ordering != Some(Ordering::Equal),
+        "<" => ordering == Some(Ordering::Less),
+        ">" => ordering == Some(Ordering::Greater),
+        "<=" => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        ">=" => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        _ => return None,
+    })
+}
+
+/// Context paths referenced by a condition, for variable extraction.
+pub fn condition_paths(cond: &str) -> Vec<&str> {
+    let cond = cond.trim();
+    let operands = match split_comparison(cond) {
+        Some((lhs, _, rhs)) => vec![lhs, rhs],
+        None => vec![cond],
+    };
+    operands
+        .into_iter()
+        .filter(|operand| !operand.is_empty() && !is_literal(operand))
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token<'a> {
+    Number(f64),
+    Path(&'a str),
+    Op(char),
+    Open,
+    Close,
+}
+
+fn tokenize(expr: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = expr.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        match c {
+            _ if c.is_whitespace() => {}
+            '+' | '-' | '*' | '/' | '%' => tokens.push(Token::Op(c)),
+            '(' => tokens.push(Token::Open),
+            ')' => tokens.push(Token::Close),
+            _ if c.is_ascii_alphanumeric() || "_.[]".contains(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, next)) = chars.peek() {
+                    if !(next.is_ascii_alphanumeric() || "_.[]".contains(next)) {
+                        break;
+                    }
+                    end = i + next.len_utf8();
+                    chars.next();
+                }
+                let word = &expr[start..end];
+                tokens.push(match parse_number(word) {
+                    Some(n) => Token::Number(n),
+                    None => Token::Path(word),
+                });
+            }
+            _ => return Err(format!("Unexpected '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive-descent evaluator for `+ - * / %` with the usual precedence,
+/// unary minus and parentheses.
+struct Arithmetic<'a, 'c> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    context: &'c Value,
+}
+
+impl<'a> Arithmetic<'a, '_> {
+    fn peek(&self) -> Option<&Token<'a>> {
+        self.tokens.get(self.pos)
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut acc = self.product()?;
+        while let Some(Token::Op(op @ ('+' | '-'))) = self.peek().cloned() {
+            self.pos += 1;
+            let rhs = self.product()?;
+            acc = if op == '+' { acc + rhs } else { acc - rhs };
+        }
+        Ok(acc)
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut acc = self.unary()?;
+        while let Some(Token::Op(op @ ('*' | '/' | '%'))) = self.peek().cloned() {
+            self.pos += 1;
+            let rhs = self.unary()?;
+            if op != '*' && rhs == 0.0 {
+                return Err("Division by zero".to_string());
+            }
+            acc = match op {
+                '*' => acc * rhs,
+                '/' => acc / rhs,
+                _ => acc % rhs,
+            };
+        }
+        Ok(acc)
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.peek() == Some(&Token::Op('-')) {
+            self.pos += 1;
+            return Ok(-self.unary()?);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<f64, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Path(path)) => resolve_path(self.context, path)
+                .and_then(as_number)
+                .ok_or_else(|| format!("'{}' is not a number", path)),
+            Some(Token::Open) => {
+                let value = self.sum()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err("Missing ')'".to_string());
+                }
+                self.pos += 1;
+                Ok(value)
+            }
+            _ => Err("Incomplete expression".to_string()),
+        }
+    }
+}
+
+/// Evaluates an arithmetic output expression such as `count + 1` or
+/// `total / 1024`. Whole results become integers, anything else a float.
+pub fn evaluate_arithmetic(expr: &str, context: &Value) -> Result<Value, String> {
+    let mut arithmetic = Arithmetic {
+        tokens: tokenize(expr)?,
+        pos: 0,
+        context,
+    };
+    let result = arithmetic.sum()?;
+    if arithmetic.pos < arithmetic.tokens.len() {
+        return Err("Unexpected input after the expression".to_string());
+    }
+
+    if result.fract() == 0.0 && result.abs() < i64::MAX as f64 {
+        Ok(Value::from(result as i64))
+    } else {
+        serde_json::Number::from_f64(result)
+            .map(Value::Number)
+            .ok_or_else(|| "Result is not a finite number".to_string())
+    }
+}
+
+/// Splits `cond ? a : b` at the `?` and `:` outside of quotes.
+pub fn split_ternary(expr: &str) -> Option<(&str, &str, &str)> {
+    let mut in_quotes = false;
+    let mut question = None;
+    for (i, c) in expr.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            _ if in_quotes => {}
+            '?' if question.is_none() => question = Some(i),
+            ':' if question.is_some() => {
+                let q = question.unwrap();
+                return Some((
+                    expr[..q].trim(),
+                    expr[q + 1..i].trim(),
+                    expr[i + 1..].trim(),
+                ));
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Context paths referenced by an output expression, for variable extraction.
+pub fn expression_paths(expr: &str) -> Vec<&str> {
+    if let Some((cond, then, otherwise)) = split_ternary(expr) {
+        let mut paths = condition_paths(cond);
+        for branch in [then, otherwise] {
+            if !is_literal(branch) {
+                paths.extend(expression_paths(branch));
+            }
+        }
+        return paths;
+    }
+    tokenize(expr)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|token| match token {
+            Token::Path(path) => Some(path),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn number_like_words_are_variables() {
+        let context = json!({ "inf": 2, "nan": 3, "infinity": 4 });
+        assert_eq!(evaluate_arithmetic("inf + 1", &context), Ok(json!(3)));
+        assert_eq!(evaluate_arithmetic("nan * 2", &context), Ok(json!(6)));
+        assert_eq!(evaluate_arithmetic("infinity - 1", &context), Ok(json!(3)));
+        assert!(evaluate_condition("inf == 2", &context).unwrap());
+    }
+
+    #[test]
+    fn decimal_literals_are_numbers() {
+        let context = json!({});
+        assert_eq!(evaluate_arithmetic(".5 * 4", &context), Ok(json!(2)));
+        assert_eq!(evaluate_arithmetic("1e3 + 1", &context), Ok(json!(1001)));
+        assert_eq!(evaluate_arithmetic("-2.5 + 1", &context), Ok(json!(-1.5)));
+    }
+
+    #[test]
+    fn number_like_strings_compare_as_text() {
+        let context = json!({ "status": "nan", "count": "-3" });
+        assert!(evaluate_condition("status == \"nan\"", &context).unwrap());
+        assert!(evaluate_condition("count < 0", &context).unwrap());
+    }
+}
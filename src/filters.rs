@@ -0,0 +1,1090 @@
+use crate::builtin_fns::run_shell_with_input;
+use crate::engine::resolve_path;
+use crate::expr;
+use crate::plugins::Plugin;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime};
+use md5::Md5;
+use regex::Regex;
+use serde_json::{Value, json};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+use tiktoken_rs::CoreBPE;
+
+fn as_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Null => String::new(),
+        other => other.to_string(),
+    }
+}
+
+/// Recursively lays `overlay` over `base`. Nested objects are merged, any
+/// other value from `overlay` replaces the one in `base`.
+fn deep_merge(base: &mut Value, overlay: &Value) {
+    match (base, overlay) {
+        (Value::Object(base_map), Value::Object(overlay_map)) => {
+            for (key, overlay_val) in overlay_map {
+                match base_map.get_mut(key) {
+                    Some(base_val) => deep_merge(base_val, overlay_val),
+                    None => {
+                        base_map.insert(key.clone(), overlay_val.clone());
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay.clone(),
+    }
+}
+
+/// Filter implementation: takes the piped value and the filter arguments.
+pub type FilterFn = fn(&Value, &[Value]) -> Result<Value, String>;
+
+/// Registered filter together with the metadata shown by `runtpl filters`.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    pub name: &'static str,
+    /// Number of arguments the filter requires; checked before `apply` is called.
+    pub arity: usize,
+    /// Number of further arguments the filter accepts but doesn't require.
+    pub optional_args: usize,
+    /// Accepts any number of arguments beyond `arity`; `optional_args` is ignored.
+    pub variadic: bool,
+    pub signature: &'static str,
+    pub description: &'static str,
+    pub apply: FilterFn,
+}
+
+static BUILTIN_FILTERS: &[Filter] = &[
+    Filter {
+        name: "upper",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "upper",
+        description: "Convert to uppercase",
+        apply: upper,
+    },
+    Filter {
+        name: "lower",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "lower",
+        description: "Convert to lowercase",
+        apply: lower,
+    },
+    Filter {
+        name: "trim",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "trim",
+        description: "Strip leading and trailing whitespace",
+        apply: trim,
+    },
+    Filter {
+        name: "title",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "title",
+        description: "Capitalize the first letter of every word",
+        apply: title,
+    },
+    Filter {
+        name: "capitalize",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "capitalize",
+        description: "Capitalize the first letter",
+        apply: capitalize,
+    },
+    Filter {
+        name: "replace",
+        arity: 2,
+        optional_args: 0,
+        variadic: false,
+        signature: "replace(from, to)",
+        description: "Replace every occurrence of a substring",
+        apply: replace,
+    },
+    Filter {
+        name: "strip_prefix",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "strip_prefix(prefix)",
+        description: "Remove a leading substring if present",
+        apply: strip_prefix,
+    },
+    Filter {
+        name: "strip_suffix",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "strip_suffix(suffix)",
+        description: "Remove a trailing substring if present",
+        apply: strip_suffix,
+    },
+    Filter {
+        name: "regex_match",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "regex_match(pattern)",
+        description: "Whether the value matches a regular expression",
+        apply: regex_match,
+    },
+    Filter {
+        name: "regex_capture",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "regex_capture(pattern)",
+        description: "First capture group (or whole match) of a regular expression",
+        apply: regex_capture,
+    },
+    Filter {
+        name: "regex_replace",
+        arity: 2,
+        optional_args: 0,
+        variadic: false,
+        signature: "regex_replace(pattern, replacement)",
+        description: "Replace all regular expression matches ($1 refers to groups)",
+        apply: regex_replace,
+    },
+    Filter {
+        name: "date",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "date(format)",
+        description: "Reformat an ISO-8601 date or unix timestamp (strftime format)",
+        apply: date,
+    },
+    Filter {
+        name: "escape_html",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "escape_html",
+        description: "Escape & < > \" ' for HTML",
+        apply: escape_html,
+    },
+    Filter {
+        name: "shell_quote",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "shell_quote",
+        description: "Quote as a single POSIX shell word",
+        apply: shell_quote,
+    },
+    Filter {
+        name: "escape_md",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "escape_md",
+        description: "Backslash-escape Markdown punctuation",
+        apply: escape_md,
+    },
+    Filter {
+        name: "truncate_chars",
+        arity: 1,
+        optional_args: 1,
+        variadic: false,
+        signature: "truncate_chars(n[, ellipsis])",
+        description: "Cut to n characters, appending ellipsis (default \"...\") if cut",
+        apply: truncate_chars,
+    },
+    Filter {
+        name: "truncate_words",
+        arity: 1,
+        optional_args: 1,
+        variadic: false,
+        signature: "truncate_words(n[, ellipsis])",
+        description: "Cut to n words, appending ellipsis (default \"...\") if cut",
+        apply: truncate_words,
+    },
+    Filter {
+        name: "indent",
+        arity: 1,
+        optional_args: 1,
+        variadic: false,
+        signature: "indent(n[, first])",
+        description: "Indent every line by n spaces (or a string); first: false skips line one",
+        apply: indent,
+    },
+    Filter {
+        name: "number_lines",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "number_lines",
+        description: "Prefix every line with its number: \"N: \"",
+        apply: number_lines,
+    },
+    Filter {
+        name: "pluralize",
+        arity: 1,
+        optional_args: 1,
+        variadic: false,
+        signature: "pluralize(singular[, plural])",
+        description: "Singular word for a count of 1, plural (default singular + \"s\") otherwise",
+        apply: pluralize,
+    },
+    Filter {
+        name: "length",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "length",
+        description: "Number of characters, array items, or object keys",
+        apply: length,
+    },
+    Filter {
+        name: "wordcount",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "wordcount",
+        description: "Number of whitespace-separated words",
+        apply: wordcount,
+    },
+    Filter {
+        name: "charcount",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "charcount",
+        description: "Number of characters (Unicode scalar values)",
+        apply: charcount,
+    },
+    Filter {
+        name: "stats",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "stats",
+        description: "Object with the number of chars, words and lines",
+        apply: stats,
+    },
+    Filter {
+        name: "token_count",
+        arity: 0,
+        optional_args: 1,
+        variadic: false,
+        signature: "token_count[(encoding)]",
+        description: "Number of LLM tokens, \"o200k\" (default) or \"cl100k\" BPE",
+        apply: token_count,
+    },
+    Filter {
+        name: "hash",
+        arity: 0,
+        optional_args: 1,
+        variadic: false,
+        signature: "hash[(algo)]",
+        description: "Hex digest: \"sha256\" (default), \"sha1\" or \"md5\"",
+        apply: hash,
+    },
+    Filter {
+        name: "default",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "default(value)",
+        description: "Use the given value when the variable is missing",
+        apply: default,
+    },
+    Filter {
+        name: "json",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "json",
+        description: "Serialize the value as compact JSON",
+        apply: json,
+    },
+    Filter {
+        name: "json_pretty",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "json_pretty",
+        description: "Serialize the value as indented JSON",
+        apply: json_pretty,
+    },
+    Filter {
+        name: "get",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "get:key",
+        description: "Array element by index or object field by key",
+        apply: get,
+    },
+    Filter {
+        name: "merge",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "merge:object",
+        description: "Deep-merge another object over the value",
+        apply: merge,
+    },
+    Filter {
+        name: "sort_by",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "sort_by(key)",
+        description: "Sort an array of objects by a field",
+        apply: sort_by,
+    },
+    Filter {
+        name: "where",
+        arity: 3,
+        optional_args: 0,
+        variadic: false,
+        signature: "where(key, op, value)",
+        description: "Keep array items whose field compares true (==, !=, <, >, <=, >=)",
+        apply: where_,
+    },
+    Filter {
+        name: "zip",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "zip(other)",
+        description: "Pair items with another array: [[a0, b0], [a1, b1], ...]",
+        apply: zip,
+    },
+    Filter {
+        name: "enumerate",
+        arity: 0,
+        optional_args: 0,
+        variadic: false,
+        signature: "enumerate",
+        description: "Pair items with their index: [[0, a0], [1, a1], ...]",
+        apply: enumerate,
+    },
+    Filter {
+        name: "take",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "take(n)",
+        description: "First n items of an array",
+        apply: take,
+    },
+    Filter {
+        name: "skip",
+        arity: 1,
+        optional_args: 0,
+        variadic: false,
+        signature: "skip(n)",
+        description: "All but the first n items of an array",
+        apply: skip,
+    },
+];
+
+fn upper(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(as_text(value).to_uppercase()))
+}
+
+fn lower(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(as_text(value).to_lowercase()))
+}
+
+fn trim(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(as_text(value).trim().to_string()))
+}
+
+/// Uppercases the first letter and leaves the rest unchanged
+fn capitalize_word(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Capitalizes every word; the whitespace between words is kept as is
+fn title(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    let text = as_text(value);
+    let mut result = String::with_capacity(text.len());
+    let mut at_word_start = true;
+    for c in text.chars() {
+        if at_word_start && !c.is_whitespace() {
+            result.extend(c.to_uppercase());
+        } else {
+            result.push(c);
+        }
+        at_word_start = c.is_whitespace();
+    }
+    Ok(Value::String(result))
+}
+
+fn capitalize(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(capitalize_word(&as_text(value))))
+}
+
+fn replace(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let from = as_text(&args[0]);
+    if from.is_empty() {
+        return Err("Filter 'replace' cannot replace an empty string".to_string());
+    }
+    Ok(Value::String(
+        as_text(value).replace(&from, &as_text(&args[1])),
+    ))
+}
+
+fn strip_prefix(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let text = as_text(value);
+    let prefix = as_text(&args[0]);
+    Ok(Value::String(
+        text.strip_prefix(prefix.as_str())
+            .unwrap_or(&text)
+            .to_string(),
+    ))
+}
+
+fn strip_suffix(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let text = as_text(value);
+    let suffix = as_text(&args[0]);
+    Ok(Value::String(
+        text.strip_suffix(suffix.as_str())
+            .unwrap_or(&text)
+            .to_string(),
+    ))
+}
+
+fn compile_regex(pattern: &Value, filter: &str) -> Result<Regex, String> {
+    let pattern = pattern
+        .as_str()
+        .ok_or_else(|| format!("Filter '{}' expects the pattern as a string", filter))?;
+    Regex::new(pattern).map_err(|e| format!("Invalid pattern in filter '{}': {}", filter, e))
+}
+
+fn regex_match(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let re = compile_regex(&args[0], "regex_match")?;
+    Ok(Value::Bool(re.is_match(&as_text(value))))
+}
+
+/// The first capture group, or the whole match without groups; `null` without a match
+fn regex_capture(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let re = compile_regex(&args[0], "regex_capture")?;
+    let text = as_text(value);
+    Ok(re
+        .captures(&text)
+        .and_then(|caps| caps.get(1).or_else(|| caps.get(0)))
+        .map(|m| Value::String(m.as_str().to_string()))
+        .unwrap_or(Value::Null))
+}
+
+fn regex_replace(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let re = compile_regex(&args[0], "regex_replace")?;
+    let replacement = as_text(&args[1]);
+    Ok(Value::String(
+        re.replace_all(&as_text(value), replacement.as_str())
+            .into_owned(),
+    ))
+}
+
+/// Parses ISO-8601 (with or without a time zone, or a date only) or a unix
+/// timestamp in seconds. A time without a time zone is taken as UTC.
+fn parse_date(value: &Value) -> Option<DateTime<FixedOffset>> {
+    let utc = FixedOffset::east_opt(0)?;
+    if let Some(seconds) = value
+        .as_i64()
+        .or_else(|| value.as_str()?.trim().parse().ok())
+    {
+        return DateTime::from_timestamp(seconds, 0).map(|dt| dt.with_timezone(&utc));
+    }
+
+    let text = value.as_str()?.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(text) {
+        return Some(dt);
+    }
+    ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(text, "%Y-%m-%d")
+                .ok()?
+                .and_hms_opt(0, 0, 0)
+        })
+        .map(|naive| naive.and_utc().with_timezone(&utc))
+}
+
+fn date(value: &Value, args: &[Value]) -> Result<Value, String> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    let format = args[0]
+        .as_str()
+        .ok_or_else(|| "Filter 'date' expects the format as a string".to_string())?;
+    let items: Vec<Item> = StrftimeItems::new(format).collect();
+    if items.contains(&Item::Error) {
+        return Err(format!("Invalid date format '{}'", format));
+    }
+    let parsed = parse_date(value).ok_or_else(|| {
+        format!(
+            "Filter 'date' cannot parse '{}' as a date or timestamp",
+            as_text(value)
+        )
+    })?;
+    Ok(Value::String(
+        parsed.format_with_items(items.into_iter()).to_string(),
+    ))
+}
+
+fn escape_html(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    let text = as_text(value);
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    Ok(Value::String(escaped))
+}
+
+/// Single quotes, inside which the shell interprets nothing; a `'` itself
+/// is written as `'\''`
+fn shell_quote(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::String(format!(
+        "'{}'",
+        as_text(value).replace('\'', r"'\''")
+    )))
+}
+
+fn escape_md(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    const SPECIAL: &str = "\\`*_{}[]()<>#+-.!|~";
+    let text = as_text(value);
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if SPECIAL.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    Ok(Value::String(escaped))
+}
+
+const DEFAULT_ELLIPSIS: &str = "...";
+
+fn ellipsis_arg(args: &[Value]) -> String {
+    args.get(1)
+        .map(as_text)
+        .unwrap_or_else(|| DEFAULT_ELLIPSIS.to_string())
+}
+
+/// The ellipsis marker is only added when the text is actually cut, and
+/// doesn't count towards the limit
+fn truncate_chars(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let limit = count_arg(&args[0], "truncate_chars")?;
+    let text = as_text(value);
+    match text.char_indices().nth(limit) {
+        Some((cut, _)) => Ok(Value::String(format!(
+            "{}{}",
+            &text[..cut],
+            ellipsis_arg(args)
+        ))),
+        None => Ok(Value::String(text)),
+    }
+}
+
+/// Keeps the original spaces and line breaks between the words that remain
+fn truncate_words(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let limit = count_arg(&args[0], "truncate_words")?;
+    let text = as_text(value);
+    let mut words = text.split_whitespace();
+    let Some(last_kept) = words.by_ref().take(limit).last() else {
+        return Ok(Value::String(if text.trim().is_empty() {
+            text
+        } else {
+            ellipsis_arg(args)
+        }));
+    };
+    if words.next().is_none() {
+        return Ok(Value::String(text));
+    }
+    let cut = last_kept.as_ptr() as usize - text.as_ptr() as usize + last_kept.len();
+    Ok(Value::String(format!(
+        "{}{}",
+        &text[..cut],
+        ellipsis_arg(args)
+    )))
+}
+
+/// Empty lines are not indented, so no trailing whitespace is left behind
+fn indent(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let prefix = match &args[0] {
+        Value::String(prefix) => prefix.clone(),
+        n => " ".repeat(count_arg(n, "indent")?),
+    };
+    let indent_first = match args.get(1) {
+        None => true,
+        Some(Value::Bool(first)) => *first,
+        Some(_) => return Err("Filter 'indent' expects true or false as 'first'".to_string()),
+    };
+
+    let text = as_text(value);
+    let mut result = String::with_capacity(text.len());
+    for (i, line) in text.split_inclusive('\n').enumerate() {
+        if (i > 0 || indent_first) && !line.trim_end_matches(['\r', '\n']).is_empty() {
+            result.push_str(&prefix);
+        }
+        result.push_str(line);
+    }
+    Ok(Value::String(result))
+}
+
+/// Numbers are padded to the width of the last one, so the lines start in one column
+fn number_lines(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    let text = as_text(value);
+    let lines: Vec<&str> = text.split_inclusive('\n').collect();
+    let width = lines.len().to_string().len();
+    let mut result = String::with_capacity(text.len() + lines.len() * (width + 2));
+    for (i, line) in lines.iter().enumerate() {
+        result.push_str(&format!("{:>width$}: {}", i + 1, line, width = width));
+    }
+    Ok(Value::String(result))
+}
+
+/// The value is a count (a number, a numeric string or an array); exactly 1 is singular
+fn pluralize(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let count = match value {
+        Value::Array(arr) => Some(arr.len() as f64),
+        Value::Number(n) => n.as_f64(),
+        other => as_text(other).trim().parse().ok(),
+    }
+    .ok_or_else(|| {
+        format!(
+            "Filter 'pluralize' expects a count, got '{}'",
+            as_text(value)
+        )
+    })?;
+
+    let singular = as_text(&args[0]);
+    Ok(Value::String(if count == 1.0 {
+        singular
+    } else {
+        args.get(1)
+            .map(as_text)
+            .unwrap_or_else(|| format!("{}s", singular))
+    }))
+}
+
+fn length(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    let len = match value {
+        Value::Array(arr) => arr.len(),
+        Value::Object(map) => map.len(),
+        other => as_text(other).chars().count(),
+    };
+    Ok(Value::from(len))
+}
+
+/// Number of words: non-empty tokens separated by whitespace
+fn wordcount(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::from(as_text(value).split_whitespace().count()))
+}
+
+/// Number of characters, counted in Unicode scalar values rather than bytes
+fn charcount(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::from(as_text(value).chars().count()))
+}
+
+/// A `{chars, words, lines}` object for the text; a last line without a line break counts too
+pub(crate) fn text_stats(text: &str) -> Value {
+    json!({
+        "chars": text.chars().count(),
+        "words": text.split_whitespace().count(),
+        "lines": text.lines().count(),
+    })
+}
+
+fn stats(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(text_stats(&as_text(value)))
+}
+
+/// BPE vocabularies are expensive to build, so each is built once, on first use
+static O200K: OnceLock<CoreBPE> = OnceLock::new();
+static CL100K: OnceLock<CoreBPE> = OnceLock::new();
+
+/// Number of tokens in the "o200k" (GPT-4o) or "cl100k" (GPT-4, GPT-3.5) encoding
+pub(crate) fn count_tokens(text: &str, encoding: &str) -> Result<usize, String> {
+    let bpe = match encoding {
+        "o200k" | "o200k_base" => O200K.get_or_init(|| tiktoken_rs::o200k_base().unwrap()),
+        "cl100k" | "cl100k_base" => CL100K.get_or_init(|| tiktoken_rs::cl100k_base().unwrap()),
+        other => {
+            return Err(format!(
+                "Unknown token encoding '{}', expected \"o200k\" or \"cl100k\"",
+                other
+            ));
+        }
+    };
+    Ok(bpe.encode_with_special_tokens(text).len())
+}
+
+fn token_count(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let encoding = args.first().map_or("o200k".to_string(), as_text);
+    count_tokens(&as_text(value), &encoding).map(Value::from)
+}
+
+/// Hex digest of `bytes` with the "sha256", "sha1" or "md5" algorithm
+pub(crate) fn hex_digest(bytes: &[u8], algo: &str) -> Result<String, String> {
+    let digest = match algo {
+        "sha256" => Sha256::digest(bytes).to_vec(),
+        "sha1" => Sha1::digest(bytes).to_vec(),
+        "md5" => Md5::digest(bytes).to_vec(),
+        other => {
+            return Err(format!(
+                "Unknown hash algorithm '{}', expected \"sha256\", \"sha1\" or \"md5\"",
+                other
+            ));
+        }
+    };
+    Ok(digest.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+fn hash(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let algo = args.first().map_or("sha256".to_string(), as_text);
+    hex_digest(as_text(value).as_bytes(), &algo).map(Value::String)
+}
+
+/// Unlike regular output, strings are serialized too (in quotes)
+fn json(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    serde_json::to_string(value)
+        .map(Value::String)
+        .map_err(|e| e.to_string())
+}
+
+fn json_pretty(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    serde_json::to_string_pretty(value)
+        .map(Value::String)
+        .map_err(|e| e.to_string())
+}
+
+/// Uses the argument when the value is missing (`null`)
+fn default(value: &Value, args: &[Value]) -> Result<Value, String> {
+    match value {
+        Value::Null => Ok(args[0].clone()),
+        other => Ok(other.clone()),
+    }
+}
+
+/// Dynamic access by index or key, e.g. `emails | get:loop.index`
+fn get(value: &Value, args: &[Value]) -> Result<Value, String> {
+    match (value, &args[0]) {
+        (Value::Array(arr), Value::Number(n)) => Ok(n
+            .as_u64()
+            .and_then(|i| arr.get(i as usize))
+            .cloned()
+            .unwrap_or(Value::Null)),
+        (Value::Object(map), Value::String(key)) => {
+            Ok(map.get(key).cloned().unwrap_or(Value::Null))
+        }
+        _ => Err(
+            "Filter 'get' expects an array with a numeric index or an object with a string key"
+                .to_string(),
+        ),
+    }
+}
+
+fn merge(value: &Value, args: &[Value]) -> Result<Value, String> {
+    if !value.is_object() || !args[0].is_object() {
+        return Err("Filter 'merge' can only merge two objects".to_string());
+    }
+    let mut merged = value.clone();
+    deep_merge(&mut merged, &args[0]);
+    Ok(merged)
+}
+
+fn as_array<'a>(value: &'a Value, filter: &str) -> Result<&'a [Value], String> {
+    match value {
+        Value::Array(arr) => Ok(arr),
+        Value::Null => Ok(&[]),
+        _ => Err(format!(
+            "Filter '{}' can only be applied to an array",
+            filter
+        )),
+    }
+}
+
+fn field_path<'a>(arg: &'a Value, filter: &str) -> Result<&'a str, String> {
+    arg.as_str()
+        .ok_or_else(|| format!("Filter '{}' expects the field name as a string", filter))
+}
+
+/// Stable sort by a field of the items; items without the field come first
+fn sort_by(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let path = field_path(&args[0], "sort_by")?;
+    let mut items = as_array(value, "sort_by")?.to_vec();
+    items.sort_by(|a, b| {
+        let a = resolve_path(a, path).unwrap_or(&Value::Null);
+        let b = resolve_path(b, path).unwrap_or(&Value::Null);
+        expr::compare(a, b).unwrap_or(Ordering::Equal)
+    });
+    Ok(Value::Array(items))
+}
+
+/// Keeps the items for which `field OP value` holds, like in `{{if}}`
+fn where_(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let path = field_path(&args[0], "where")?;
+    let op = args[1].as_str().unwrap_or_default();
+    let mut kept = Vec::new();
+    for item in as_array(value, "where")? {
+        let field = resolve_path(item, path).unwrap_or(&Value::Null);
+        match expr::compare_with(field, op, &args[2]) {
+            Some(true) => kept.push(item.clone()),
+            Some(false) => {}
+            None => return Err(format!("Filter 'where' got an unknown operator '{}'", op)),
+        }
+    }
+    Ok(Value::Array(kept))
+}
+
+/// Pairs up to the end of the shorter array
+fn zip(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let other = as_array(&args[0], "zip")?;
+    Ok(Value::Array(
+        as_array(value, "zip")?
+            .iter()
+            .zip(other)
+            .map(|(a, b)| Value::Array(vec![a.clone(), b.clone()]))
+            .collect(),
+    ))
+}
+
+fn enumerate(value: &Value, _args: &[Value]) -> Result<Value, String> {
+    Ok(Value::Array(
+        as_array(value, "enumerate")?
+            .iter()
+            .enumerate()
+            .map(|(i, item)| Value::Array(vec![Value::from(i), item.clone()]))
+            .collect(),
+    ))
+}
+
+fn count_arg(arg: &Value, filter: &str) -> Result<usize, String> {
+    arg.as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| format!("Filter '{}' expects a non-negative integer", filter))
+}
+
+fn take(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let n = count_arg(&args[0], "take")?;
+    let items = as_array(value, "take")?;
+    Ok(Value::from(&items[..n.min(items.len())]))
+}
+
+fn skip(value: &Value, args: &[Value]) -> Result<Value, String> {
+    let n = count_arg(&args[0], "skip")?;
+    let items = as_array(value, "skip")?;
+    Ok(Value::from(&items[n.min(items.len())..]))
+}
+
+/// The set of filters available to `render`. Starts out with the built-in
+/// filters; library users can add their own with `register`, and shell
+/// commands can be used as filters with `register_command`.
+#[derive(Debug, Clone)]
+pub struct FilterRegistry {
+    filters: Vec<Filter>,
+    external: BTreeMap<String, ExternalFilter>,
+}
+
+/// A filter implemented outside of runtpl: a shell command or a plugin.
+#[derive(Debug, Clone)]
+enum ExternalFilter {
+    Command(String),
+    Plugin(Arc<dyn Plugin>),
+}
+
+impl Default for FilterRegistry {
+    fn default() -> Self {
+        FilterRegistry {
+            filters: BUILTIN_FILTERS.to_vec(),
+            external: BTreeMap::new(),
+        }
+    }
+}
+
+/// Runs the value through a filter command: a string goes to stdin as is, other
+/// values as JSON, and the filter arguments are appended to the command, quoted.
+/// The result is stdout without trailing line breaks.
+fn run_filter_command(cmd: &str, value: &Value, args: &[Value]) -> Result<Value, String> {
+    let mut command_line = cmd.to_string();
+    for arg in args {
+        command_line.push(' ');
+        command_line.push_str(&as_text(&shell_quote(arg, &[])?));
+    }
+
+    let output = run_shell_with_input(&command_line, as_text(value).as_bytes())
+        .map_err(|e| format!("Could not run '{}': {}", cmd, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Command '{}' failed ({}): {}",
+            cmd,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(Value::String(
+        stdout.trim_end_matches(['\r', '\n']).to_string(),
+    ))
+}
+
+impl FilterRegistry {
+    /// Adds a filter, replacing any existing filter with the same name.
+    pub fn register(&mut self, filter: Filter) {
+        match self.filters.iter_mut().find(|f| f.name == filter.name) {
+            Some(existing) => *existing = filter,
+            None => self.filters.push(filter),
+        }
+    }
+
+    /// Makes the shell command `cmd` available as the filter `name`. The piped
+    /// value goes to the command's stdin, filter arguments are appended to the
+    /// command line, and its stdout becomes the result. Filters registered with
+    /// `register` keep precedence.
+    pub fn register_command(&mut self, name: &str, cmd: &str) {
+        self.external
+            .insert(name.to_string(), ExternalFilter::Command(cmd.to_string()));
+    }
+
+    /// Makes the filter `name` of `plugin` available. Filters registered with
+    /// `register` keep precedence.
+    pub fn register_plugin(&mut self, name: &str, plugin: Arc<dyn Plugin>) {
+        self.external
+            .insert(name.to_string(), ExternalFilter::Plugin(plugin));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Filter> {
+        self.filters.iter().find(|filter| filter.name == name)
+    }
+
+    /// Whether `name` resolves to a filter added with `register_command` or
+    /// `register_plugin`, rather than a built-in one.
+    pub fn is_external(&self, name: &str) -> bool {
+        self.get(name).is_none() && self.external.contains_key(name)
+    }
+
+    /// Whether `name` resolves to a shell command added with `register_command`.
+    pub fn is_command(&self, name: &str) -> bool {
+        self.get(name).is_none()
+            && matches!(self.external.get(name), Some(ExternalFilter::Command(_)))
+    }
+
+    /// The filters added with `register_command` and `register_plugin`, as
+    /// `(name, description)` in name order.
+    pub fn external(&self) -> impl Iterator<Item = (&str, String)> {
+        self.external.iter().map(|(name, filter)| {
+            let description = match filter {
+                ExternalFilter::Command(cmd) => format!("Runs `{}`", cmd),
+                ExternalFilter::Plugin(plugin) => {
+                    format!("From plugin {}", plugin.path().display())
+                }
+            };
+            (name.as_str(), description)
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Filter> {
+        self.filters.iter()
+    }
+
+    /// Applies the filter `name` to `value` (`{{ value | name }}`, `{{ value | name:arg }}`
+    /// or `{{ value | name(arg, ...) }}`).
+    pub fn apply(&self, name: &str, value: &Value, args: &[Value]) -> Result<Value, String> {
+        let Some(filter) = self.get(name) else {
+            let result = match self.external.get(name) {
+                Some(ExternalFilter::Command(cmd)) => run_filter_command(cmd, value, args),
+                Some(ExternalFilter::Plugin(plugin)) => plugin.apply_filter(name, value, args),
+                None => return Err(format!("Unknown filter '{}'", name)),
+            };
+            return result.map_err(|e| format!("Error in filter '{}': {}", name, e));
+        };
+
+        let max_args = filter.arity + filter.optional_args;
+        if args.len() < filter.arity || (!filter.variadic && args.len() > max_args) {
+            let expected = if filter.variadic {
+                format!("at least {}", filter.arity)
+            } else if filter.optional_args == 0 {
+                filter.arity.to_string()
+            } else {
+                format!("{} to {}", filter.arity, max_args)
+            };
+            return Err(format!(
+                "Filter '{}' expects {} argument(s), got {}",
+                name,
+                expected,
+                args.len()
+            ));
+        }
+        (filter.apply)(value, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wordcount_ignores_repeated_whitespace() {
+        let text = json!("  one   two\t\tthree\n\nfour  ");
+        assert_eq!(wordcount(&text, &[]).unwrap(), json!(4));
+        assert_eq!(wordcount(&json!("   "), &[]).unwrap(), json!(0));
+    }
+
+    #[test]
+    fn counts_multibyte_characters_once() {
+        let text = json!("héllo wörld 日本語 🦀");
+        assert_eq!(charcount(&text, &[]).unwrap(), json!(17));
+        assert_eq!(wordcount(&text, &[]).unwrap(), json!(4));
+    }
+
+    #[test]
+    fn merge_combines_nested_objects() {
+        let base = json!({ "server": { "host": "localhost", "port": 80 }, "debug": false });
+        let overrides = json!({ "server": { "port": 8080 }, "name": "app" });
+        assert_eq!(
+            merge(&base, &[overrides]).unwrap(),
+            json!({
+                "server": { "host": "localhost", "port": 8080 },
+                "debug": false,
+                "name": "app"
+            })
+        );
+    }
+
+    #[test]
+    fn merge_lets_the_overlay_win_conflicts() {
+        let base = json!({ "server": { "host": "localhost" }, "tags": ["a"] });
+        let overrides = json!({ "server": "remote", "tags": { "first": "b" } });
+        assert_eq!(
+            merge(&base, &[overrides]).unwrap(),
+            json!({ "server": "remote", "tags": { "first": "b" } })
+        );
+    }
+
+    #[test]
+    fn merge_rejects_non_objects() {
+        let err = merge(&json!({ "a": 1 }), &[json!([1, 2])]).unwrap_err();
+        assert_eq!(err, "Filter 'merge' can only merge two objects");
+        assert!(merge(&json!("text"), &[json!({})]).is_err());
+    }
+}
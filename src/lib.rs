@@ -0,0 +1,17 @@
+//! Template engine behind the `runtpl` CLI. The `engine` module renders
+//! templates against a `Context`; `filters` holds the filters usable in
+//! `{{ value | filter }}` tags and lets embedders register their own.
+
+mod blocks;
+pub mod builtin_fns;
+pub mod capability;
+pub mod config;
+pub mod context;
+pub mod engine;
+pub mod error;
+mod expr;
+pub mod filters;
+pub mod plugins;
+mod script;
+pub mod template_manager;
+pub mod warnings;
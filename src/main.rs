@@ -1,35 +1,46 @@
-mod builtin_fns;
 mod cli;
-mod context;
-mod engine;
-mod error;
-mod template_manager;
-
-use clap::Parser;
-use cli::{Cli, Commands, TemplateCommands};
-use context::Context;
-use engine::VarUsage;
-use error::AppError;
-use serde_json::{Map, Value};
+
+use clap::builder::PossibleValuesParser;
+use clap::{Arg, CommandFactory, Parser};
+use clap_complete::Shell;
+use cli::{Cli, Commands, RunArgs, TemplateCommands};
+use dialoguer::{BasicHistory, Input};
+use runtpl::capability::Capability;
+use runtpl::config::Config;
+use runtpl::context::Context;
+use runtpl::engine::{Engine, VarUsage};
+use runtpl::error::AppError;
+use runtpl::{plugins, template_manager, warnings};
+use serde_json::{Map, Value, json};
+use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// How often `--watch` checks the watched files for changes.
+const WATCH_INTERVAL: Duration = Duration::from_millis(300);
 
 fn main() -> Result<(), ()> {
     let cli = Cli::parse();
 
     let result = match cli.command {
-        Commands::Run {
-            template_name,
-            args,
-            interactive,
-            no_copy,
-        } => run_command(template_name, args, interactive, no_copy),
+        Commands::Run(run_args) => run_command(run_args),
+        Commands::Filters => list_filters(),
         Commands::Template { command } => match command {
             TemplateCommands::List => template_manager::list_templates(),
             TemplateCommands::New { name } => template_manager::new_template(&name),
-            TemplateCommands::Edit { name } => template_manager::edit_template(&name),
+            TemplateCommands::Edit { name, create } => {
+                template_manager::edit_template(&name, create)
+            }
             TemplateCommands::Remove { name } => template_manager::remove_template(&name),
         },
+        Commands::Vars {
+            template_name,
+            json,
+        } => list_variables(&template_name, json),
+        Commands::Completions { shell } => print_completions(shell),
     };
 
     if let Err(AppError::InteractiveAbort(msg)) = result {
@@ -45,49 +56,655 @@ fn main() -> Result<(), ()> {
     Ok(())
 }
 
-fn run_command(
-    template_name: String,
-    args: Vec<String>,
-    interactive: bool,
-    no_copy: bool,
-) -> Result<(), AppError> {
-    let template_path = template_manager::resolve_template_path(&template_name)?;
+/// Handles the `vars` command: prints the variables the template needs
+/// with their shape, as an indented list or as JSON.
+fn list_variables(template_name: &str, json: bool) -> Result<(), AppError> {
+    let template_path = template_manager::resolve_template_path(template_name)?;
+    let template_content = fs::read_to_string(&template_path)?;
+    let helpers = template_manager::resolve_helpers_path(&template_path)
+        .map(fs::read_to_string)
+        .transpose()?;
+    let variables = Engine::default()
+        .extract_variables(&content_to_analyze(&template_content, helpers.as_deref()));
+
+    if json {
+        let mut value = serde_json::to_value(&variables)?;
+        value.sort_all_objects();
+        println!("{}", serde_json::to_string_pretty(&value)?);
+        return Ok(());
+    }
+    if variables.is_empty() {
+        println!("No variables found in the template.");
+        return Ok(());
+    }
+    print_variables(&variables, 0);
+    Ok(())
+}
+
+fn print_variables(variables: &HashMap<String, VarUsage>, depth: usize) {
+    let mut variables: Vec<_> = variables.iter().collect();
+    variables.sort_by_key(|(name, _)| *name);
+    for (name, usage) in variables {
+        let shape = match usage {
+            VarUsage::Simple => "value",
+            VarUsage::CollectionOfSimple => "list of values",
+            VarUsage::CollectionOfObjects(_) => "list of objects",
+        };
+        println!("{}{}: {}", "  ".repeat(depth), name, shape);
+        if let VarUsage::CollectionOfObjects(fields) = usage {
+            print_variables(fields, depth + 1);
+        }
+    }
+}
+
+/// Prints the completion script for `shell`. The names of the templates
+/// stored at this point are completed where a template name is expected.
+fn print_completions(shell: Shell) -> Result<(), AppError> {
+    let names = template_names_for_completion();
+    // `mut_arg` moves an argument to the end, so positionals get explicit indices
+    let complete_names = |arg: Arg| {
+        arg.index(1)
+            .value_parser(PossibleValuesParser::new(names.clone()))
+    };
+
+    let mut command = Cli::command()
+        .mut_subcommand("run", |run| {
+            run.mut_arg("template_name", complete_names)
+                .mut_arg("args", |args| args.index(2))
+        })
+        .mut_subcommand("template", |template| {
+            template
+                .mut_subcommand("edit", |edit| edit.mut_arg("name", complete_names))
+                .mut_subcommand("remove", |remove| remove.mut_arg("name", complete_names))
+        });
+    let bin_name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, bin_name, &mut io::stdout());
+    Ok(())
+}
+
+/// Stored template names, or none if the template directory can't be read:
+/// a completion script is still useful without them.
+fn template_names_for_completion() -> Vec<String> {
+    let mut names = template_manager::template_names().unwrap_or_default();
+    names.sort();
+    names
+}
+
+fn run_command(run_args: RunArgs) -> Result<(), AppError> {
+    if run_args.watch {
+        return watch(run_args);
+    }
+    if run_args.output_json {
+        return run_with_json_envelope(&run_args);
+    }
+    if run_args.dry_run {
+        return dry_run(&run_args);
+    }
+
+    if let (Some(dir), Some(name_template)) = (&run_args.output_dir, &run_args.output_name) {
+        return run_to_output_dir(&run_args, dir, name_template);
+    }
+    if let Some(path) = &run_args.output {
+        check_output_path(path, run_args.force)?;
+    }
+    let job = load_template_and_context(&run_args)?;
+    let engine = build_engine(&run_args)?;
+
+    let result = job.render(&engine).map_err(AppError::Render)?;
+    if let Some(path) = &run_args.output {
+        fs::write(path, result)?;
+        if !run_args.quiet {
+            eprintln!("(Result written to {})", path.display());
+        }
+        return Ok(());
+    }
+
+    print!("{}", result);
+
+    if !run_args.no_copy {
+        copy_to_clipboard(result, run_args.quiet);
+    }
+    Ok(())
+}
+
+/// Reports what rendering would do without calling any function, printing
+/// the result or touching the clipboard. Exits with status 1 when a variable
+/// is undefined or a call needs a capability that hasn't been granted.
+fn dry_run(run_args: &RunArgs) -> Result<(), AppError> {
+    let job = load_template_and_context(run_args)?;
+    let engine = build_engine(run_args)?;
+    let report = engine
+        .dry_run(&job.template, job.helpers.as_deref(), &job.context)
+        .map_err(AppError::Render)?;
+
+    if report.undefined.is_empty() {
+        println!("Undefined variables: none");
+    } else {
+        println!("Undefined variables ({}):", report.undefined.len());
+        for var in &report.undefined {
+            println!("  - {}", var);
+        }
+    }
+
+    if report.calls.is_empty() {
+        println!("Function calls: none");
+    } else {
+        println!("Function calls ({}):", report.calls.len());
+        for call in &report.calls {
+            let args = call
+                .args
+                .iter()
+                .map(|(name, value)| format!("{}: {}", name, value))
+                .collect::<Vec<_>>()
+                .join(", ");
+            print!("  - {}({})", call.name, args);
+            if !call.missing_capabilities.is_empty() {
+                let missing: Vec<_> = call.missing_capabilities.iter().map(|c| c.name()).collect();
+                print!(" (needs --allow {})", missing.join(","));
+            }
+            println!();
+        }
+    }
+
+    println!(
+        "Estimated output size: {} bytes, without function results",
+        report.estimated_size
+    );
+
+    let blocked = report
+        .calls
+        .iter()
+        .any(|call| !call.missing_capabilities.is_empty());
+    if !report.undefined.is_empty() || blocked {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Renders into files in `dir`: one per item of the `--each` collection, or a
+/// single one. Everything is rendered before the first file is written, so a
+/// failing item leaves no partial set of files behind. With `--keep-going`,
+/// the items that rendered are written and the failures are reported at the end.
+fn run_to_output_dir(run_args: &RunArgs, dir: &Path, name_template: &str) -> Result<(), AppError> {
+    let job = load_template_and_context(run_args)?;
+    let engine = build_engine(run_args)?;
+
+    let contexts = match &run_args.each {
+        Some(path) => item_contexts(&job.context, path)?,
+        None => vec![job.context.clone()],
+    };
+
+    let (documents, failures) = render_documents(
+        &job,
+        &engine,
+        &contexts,
+        dir,
+        name_template,
+        run_args.keep_going,
+    )?;
+
+    for (path, _) in &documents {
+        if path.exists() && !run_args.force {
+            return Err(AppError::InvalidArgument(format!(
+                "Output file '{}' already exists, pass --force to overwrite it.",
+                path.display()
+            )));
+        }
+    }
+    for (path, output) in &documents {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, output)?;
+    }
+
+    if !run_args.quiet {
+        eprintln!("({} file(s) written to {})", documents.len(), dir.display());
+    }
+    if !failures.is_empty() {
+        for failure in &failures {
+            eprintln!("{}", failure);
+        }
+        return Err(AppError::Render(format!(
+            "{} of {} item(s) failed",
+            failures.len(),
+            contexts.len()
+        )));
+    }
+    Ok(())
+}
+
+/// A file to write: its path and content.
+type Document = (PathBuf, String);
+
+/// Renders the file name and content for each context. The first failure is
+/// returned as an error, unless `keep_going` is set: then every item is
+/// attempted and the failures are returned alongside the documents that rendered.
+fn render_documents(
+    job: &RenderJob,
+    engine: &Engine,
+    contexts: &[Context],
+    dir: &Path,
+    name_template: &str,
+    keep_going: bool,
+) -> Result<(Vec<Document>, Vec<String>), AppError> {
+    let mut documents: Vec<Document> = Vec::new();
+    let mut failures = Vec::new();
+    for (index, context) in contexts.iter().enumerate() {
+        match render_document(job, engine, context, dir, name_template, &documents) {
+            Ok(document) => documents.push(document),
+            Err(e) if keep_going => failures.push(format!("Item {}: {}", index + 1, e)),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((documents, failures))
+}
+
+/// Renders one file of `--output-dir`: its path, checked to stay inside `dir`
+/// and not to clash with the `documents` rendered before it, and its content.
+fn render_document(
+    job: &RenderJob,
+    engine: &Engine,
+    context: &Context,
+    dir: &Path,
+    name_template: &str,
+    documents: &[Document],
+) -> Result<Document, AppError> {
+    let name = engine
+        .render(name_template, context)
+        .map_err(AppError::Render)?;
+    let name = PathBuf::from(name.trim());
+    if name.as_os_str().is_empty()
+        || name.is_absolute()
+        || name
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return Err(AppError::InvalidArgument(format!(
+            "Output name '{}' must be a relative path inside the output directory.",
+            name.display()
+        )));
+    }
+    let path = dir.join(name);
+    if documents.iter().any(|(existing, _)| *existing == path) {
+        return Err(AppError::InvalidArgument(format!(
+            "Several items are written to '{}', make --output-name unique per item.",
+            path.display()
+        )));
+    }
+    let output = job
+        .render_context(engine, context)
+        .map_err(AppError::Render)?;
+    Ok((path, output))
+}
+
+/// Builds one context per item of the collection at `path`: the item is
+/// available as `item`, with a `loop` object like inside `foreach`.
+fn item_contexts(context: &Context, path: &str) -> Result<Vec<Context>, AppError> {
+    let items = match context.lookup(path) {
+        Some(Value::Array(items)) => items,
+        Some(Value::Object(map)) => map.into_iter().map(|(_, value)| value).collect(),
+        Some(_) => {
+            return Err(AppError::InvalidArgument(format!(
+                "--each '{}' is not a collection.",
+                path
+            )));
+        }
+        None => {
+            return Err(AppError::InvalidArgument(format!(
+                "--each '{}' was not found in the data.",
+                path
+            )));
+        }
+    };
+
+    let length = items.len();
+    Ok(items
+        .into_iter()
+        .enumerate()
+        .map(|(index, item)| {
+            let mut item_context = context.clone();
+            item_context.0.insert("item".to_string(), item);
+            item_context.0.insert(
+                "loop".to_string(),
+                json!({
+                    "index": index,
+                    "first": index == 0,
+                    "last": index + 1 == length,
+                    "length": length,
+                }),
+            );
+            item_context
+        })
+        .collect())
+}
+
+/// Makes sure the rendered result can be written to `path`, before anything is rendered.
+fn check_output_path(path: &Path, force: bool) -> Result<(), AppError> {
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+        && !parent.is_dir()
+    {
+        return Err(AppError::InvalidArgument(format!(
+            "Output directory '{}' does not exist.",
+            parent.display()
+        )));
+    }
+    if path.is_dir() {
+        return Err(AppError::InvalidArgument(format!(
+            "Output path '{}' is a directory.",
+            path.display()
+        )));
+    }
+    if path.exists() && !force {
+        return Err(AppError::InvalidArgument(format!(
+            "Output file '{}' already exists, pass --force to overwrite it.",
+            path.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Renders once, then again every time one of the watched files changes.
+/// Errors are reported without stopping, so a half-saved file doesn't end the session.
+fn watch(run_args: RunArgs) -> Result<(), AppError> {
+    // Every render after the first replaces the files the previous one wrote
+    if (run_args.output.is_some() || run_args.output_dir.is_some()) && !run_args.force {
+        return Err(AppError::InvalidArgument(
+            "--watch rewrites its output files on every change, pass --force to allow it."
+                .to_string(),
+        ));
+    }
+    let paths = watched_paths(&run_args)?;
+    let mut last_seen = modification_times(&paths);
+
+    loop {
+        if let Err(e) = run_command(RunArgs {
+            watch: false,
+            ..run_args.clone()
+        }) {
+            eprintln!("\x1b[31;1mError:\x1b[0m {}", e);
+        }
+        if !run_args.quiet {
+            eprintln!(
+                "\n(Watching {} file(s) for changes, press Ctrl+C to stop)",
+                paths.len()
+            );
+        }
+
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            let current = modification_times(&paths);
+            if current != last_seen {
+                last_seen = current;
+                break;
+            }
+        }
+        if !run_args.quiet {
+            eprintln!("\n(Change detected, rendering again)\n");
+        }
+    }
+}
+
+/// The files a render depends on: the template, its helpers file and the
+/// `key@=path` data files.
+fn watched_paths(run_args: &RunArgs) -> Result<Vec<PathBuf>, AppError> {
+    if run_args.args.iter().any(|arg| arg.ends_with("@-")) {
+        return Err(AppError::InvalidArgument(
+            "Cannot read data from stdin (`key@-`) with --watch, use `key@=path` instead."
+                .to_string(),
+        ));
+    }
+
+    let template_name = run_args.template_name.as_deref().unwrap_or_default();
+    let template_path = template_manager::resolve_template_path(template_name)?;
+    // A helpers file created later is picked up too
+    let helpers_path = template_manager::helpers_path(&template_path);
+    let mut paths = vec![template_path];
+    paths.extend(helpers_path);
+    for arg in &run_args.args {
+        if let Some((_, path)) = arg.split_once("@=") {
+            paths.push(PathBuf::from(path));
+        }
+    }
+    Ok(paths)
+}
+
+fn modification_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+/// Renders like `run_command`, but reports the outcome as a single JSON object
+/// on stdout: `{"ok": true, "output", "warnings"}` or `{"ok": false, "error", "warnings"}`.
+fn run_with_json_envelope(run_args: &RunArgs) -> Result<(), AppError> {
+    warnings::start_capture();
+    let rendered = build_engine(run_args).and_then(|engine| {
+        let job = load_template_and_context(run_args)?;
+        job.render(&engine).map_err(AppError::Render)
+    });
+    let mut captured = warnings::take_captured();
+
+    let envelope = match rendered {
+        Ok(output) => {
+            if !run_args.no_copy
+                && let Err(msg) = try_copy_to_clipboard(output.clone())
+            {
+                captured.push(msg);
+            }
+            json!({ "ok": true, "output": output, "warnings": captured })
+        }
+        Err(e) => json!({ "ok": false, "error": e.to_string(), "warnings": captured }),
+    };
+
+    println!("{}", serde_json::to_string_pretty(&envelope)?);
+
+    if envelope["ok"] == false {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn build_engine(run_args: &RunArgs) -> Result<Engine, AppError> {
+    let mut engine = Engine::default();
+    engine.strict_functions = run_args.strict_functions;
+    engine.strict_variables = run_args.strict;
+    for list in &run_args.allow {
+        engine
+            .capabilities
+            .extend(Capability::parse_list(list).map_err(AppError::InvalidArgument)?);
+    }
+    if run_args.allow_exec {
+        engine.capabilities.insert(Capability::Exec);
+    }
+    load_extensions(&mut engine)?;
+    Ok(engine)
+}
+
+/// Adds the settings, capabilities, external functions and filters from
+/// `config.toml` and the plugins.
+fn load_extensions(engine: &mut Engine) -> Result<(), AppError> {
+    let config = Config::load()?;
+    engine.strict_variables |= config.strict;
+    for name in &config.allow {
+        engine
+            .capabilities
+            .extend(Capability::parse_list(name).map_err(AppError::Config)?);
+    }
+    engine.external_functions = config.fns;
+    for (name, cmd) in &config.filters {
+        engine.filters.register_command(name, cmd);
+    }
+    for plugin in plugins::load_dir(&plugins::default_dir()?)? {
+        engine.add_plugin(plugin);
+    }
+    Ok(())
+}
+
+/// A template to render: its text, the macros of its helpers file and its data.
+struct RenderJob {
+    template: String,
+    helpers: Option<String>,
+    context: Context,
+}
+
+impl RenderJob {
+    fn render(&self, engine: &Engine) -> Result<String, String> {
+        self.render_context(engine, &self.context)
+    }
+
+    /// Renders the template with another context, e.g. one item of `--each`.
+    fn render_context(&self, engine: &Engine, context: &Context) -> Result<String, String> {
+        match &self.helpers {
+            Some(helpers) => engine.render_with_helpers(&self.template, helpers, context),
+            None => engine.render(&self.template, context),
+        }
+    }
+}
+
+/// The text to look for variables in: the template, preceded by its helpers
+/// file, as macros from the helpers file may use variables of their own.
+fn content_to_analyze(template: &str, helpers: Option<&str>) -> String {
+    match helpers {
+        Some(helpers) => format!("{}\n{}", helpers, template),
+        None => template.to_string(),
+    }
+}
+
+/// Resolves the template and builds its context from the `run` arguments.
+fn load_template_and_context(run_args: &RunArgs) -> Result<RenderJob, AppError> {
+    if run_args.job_stdin {
+        let (template, context) = read_job_from_stdin()?;
+        return Ok(RenderJob {
+            template,
+            helpers: None,
+            context,
+        });
+    }
+
+    let template_name = run_args.template_name.as_deref().unwrap_or_default();
+    let template_path = template_manager::resolve_template_path(template_name)?;
     let template_content = fs::read_to_string(&template_path)?;
+    let helpers = template_manager::resolve_helpers_path(&template_path)
+        .map(fs::read_to_string)
+        .transpose()?;
+    let analyzed_content = content_to_analyze(&template_content, helpers.as_deref());
 
-    let context = if interactive {
-        if !args.is_empty() {
+    let context = if run_args.interactive || run_args.interactive_prompt {
+        if !run_args.args.is_empty() {
             return Err(AppError::InvalidArgument(
                 "Cannot use data arguments with --interactive mode.".to_string(),
             ));
         }
-        run_interactive_mode(&template_content)?
+        if run_args.interactive_prompt {
+            run_prompt_mode(&analyzed_content)?
+        } else {
+            run_interactive_mode(&analyzed_content)?
+        }
     } else {
-        Context::from_args(&args)?
+        let context = Context::from_args(&run_args.args)?;
+        match &run_args.var_prefix {
+            Some(prefix) => context.nest_under(prefix)?,
+            None => context,
+        }
     };
 
-    match engine::render(&template_content, &context) {
-        Ok(result) => {
-            print!("{}", result);
-
-            if !no_copy {
-                match arboard::Clipboard::new() {
-                    Ok(mut clipboard) => {
-                        if let Err(e) = clipboard.set_text(result) {
-                            eprintln!("\n\nWarning: Could not copy to clipboard: {}", e);
-                        } else {
-                            eprintln!("\n\n(Result copied to clipboard)");
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("\n\nWarning: Could not access clipboard: {}", e);
-                    }
-                }
-            }
+    Ok(RenderJob {
+        template: template_content,
+        helpers,
+        context,
+    })
+}
+
+/// Handles the `filters` command.
+fn list_filters() -> Result<(), AppError> {
+    let mut engine = Engine::default();
+    load_extensions(&mut engine)?;
+    let registry = &engine.filters;
+    let width = registry
+        .iter()
+        .map(|filter| filter.signature)
+        .chain(registry.external().map(|(name, _)| name))
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+
+    println!("Available filters:");
+    for filter in registry.iter() {
+        println!(
+            "  {:width$}  {}",
+            filter.signature,
+            filter.description,
+            width = width
+        );
+    }
+    for (name, description) in registry.external() {
+        println!("  {:width$}  {}", name, description, width = width);
+    }
+    Ok(())
+}
+
+/// Reads a render job (`{"template": "...", "context": {...}}`) from stdin.
+/// `context` may be omitted for templates without variables.
+fn read_job_from_stdin() -> Result<(String, Context), AppError> {
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    parse_job(&input)
+}
+
+/// Parses a `{"template": "...", "context": {...}}` job object.
+fn parse_job(input: &str) -> Result<(String, Context), AppError> {
+    let mut job = match serde_json::from_str(input)? {
+        Value::Object(map) => map,
+        _ => {
+            return Err(AppError::JsonParse(
+                "Job must be a JSON object with 'template' and 'context' fields.".to_string(),
+            ));
+        }
+    };
+
+    let template = match job.remove("template") {
+        Some(Value::String(template)) => template,
+        Some(_) => {
+            return Err(AppError::JsonParse(
+                "Job field 'template' must be a string.".to_string(),
+            ));
+        }
+        None => {
+            return Err(AppError::JsonParse(
+                "Job is missing the 'template' field.".to_string(),
+            ));
         }
-        Err(e) => eprintln!("Error rendering template: {}", e),
+    };
+
+    let context = match job.remove("context") {
+        Some(value) => Context::from_value(value)?,
+        None => Context::default(),
+    };
+
+    Ok((template, context))
+}
+
+/// Copies the rendered output to the clipboard. Failures are always reported,
+/// the confirmation note only when not running quietly.
+fn copy_to_clipboard(text: String, quiet: bool) {
+    match try_copy_to_clipboard(text) {
+        Ok(()) if !quiet => eprintln!("\n\n(Result copied to clipboard)"),
+        Ok(()) => {}
+        Err(msg) => eprintln!("\n\nWarning: {}", msg),
     }
+}
 
-    Ok(())
+fn try_copy_to_clipboard(text: String) -> Result<(), String> {
+    arboard::Clipboard::new()
+        .map_err(|e| format!("Could not access clipboard: {}", e))
+        .and_then(|mut clipboard| {
+            clipboard
+                .set_text(text)
+                .map_err(|e| format!("Could not copy to clipboard: {}", e))
+        })
 }
 
 fn build_json_value(usage: &VarUsage) -> Value {
@@ -108,7 +725,7 @@ fn build_json_value(usage: &VarUsage) -> Value {
 fn run_interactive_mode(template_content: &str) -> Result<Context, AppError> {
     println!("Interactive mode activated. Analyzing template...");
 
-    let variables = engine::extract_variables(template_content);
+    let variables = Engine::default().extract_variables(template_content);
 
     if variables.is_empty() {
         println!("No variables found in the template. Nothing to fill.");
@@ -156,3 +773,131 @@ fn run_interactive_mode(template_content: &str) -> Result<Context, AppError> {
     println!("Editor closed. Reading data...");
     Context::from_interactive_json(&user_data)
 }
+
+/// Asks for each variable on the terminal. Only templates made of simple
+/// variables are handled here; anything with collections goes to the editor.
+fn run_prompt_mode(template_content: &str) -> Result<Context, AppError> {
+    let variables = Engine::default().extract_variables(template_content);
+
+    if variables
+        .values()
+        .any(|usage| !matches!(usage, VarUsage::Simple))
+    {
+        println!("Template contains collections, falling back to the editor.");
+        return run_interactive_mode(template_content);
+    }
+
+    if !io::stdin().is_terminal() {
+        return Err(AppError::InvalidArgument(
+            "--interactive-prompt requires a terminal (TTY) on stdin.".to_string(),
+        ));
+    }
+
+    let mut context = Context::default();
+    let mut names: Vec<_> = variables.keys().collect();
+    names.sort();
+
+    // Earlier answers can be recalled with the arrow keys
+    let mut history = BasicHistory::new().no_duplicates(true);
+    for name in names {
+        let answer: String = Input::new()
+            .with_prompt(name)
+            .allow_empty(true)
+            .history_with(&mut history)
+            .interact_text()?;
+        context.0.insert(name.clone(), Value::String(answer));
+    }
+
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Three items whose second one fails to render (division by zero).
+    fn batch() -> (RenderJob, Vec<Context>) {
+        let context = Context::from_value(json!({ "items": [{ "n": 1 }, { "n": 0 }, { "n": 2 }] }));
+        let job = RenderJob {
+            template: "{{ 10 / item.n }}".to_string(),
+            helpers: None,
+            context: context.unwrap(),
+        };
+        let contexts = item_contexts(&job.context, "items").unwrap();
+        (job, contexts)
+    }
+
+    #[test]
+    fn keep_going_attempts_every_item() {
+        let (job, contexts) = batch();
+        let engine = Engine::default();
+        let (documents, failures) = render_documents(
+            &job,
+            &engine,
+            &contexts,
+            Path::new("out"),
+            "{{ loop.index }}.txt",
+            true,
+        )
+        .unwrap();
+
+        let outputs: Vec<_> = documents
+            .iter()
+            .map(|(_, output)| output.as_str())
+            .collect();
+        assert_eq!(outputs, ["10", "5"]);
+        assert_eq!(documents[1].0, Path::new("out/2.txt"));
+        assert_eq!(failures.len(), 1);
+        assert!(failures[0].starts_with("Item 2: "), "{}", failures[0]);
+    }
+
+    #[test]
+    fn job_object_renders_its_template() {
+        let (template, context) =
+            parse_job(r#"{"template": "Hi {{ name }}", "context": {"name": "Ann"}}"#).unwrap();
+        let rendered = Engine::default().render(&template, &context);
+        assert_eq!(rendered.unwrap(), "Hi Ann");
+    }
+
+    #[test]
+    fn job_without_context_renders_with_empty_data() {
+        let (template, context) = parse_job(r#"{"template": "plain"}"#).unwrap();
+        assert_eq!(
+            Engine::default().render(&template, &context).unwrap(),
+            "plain"
+        );
+    }
+
+    #[test]
+    fn malformed_jobs_are_rejected() {
+        let error = |input| parse_job(input).unwrap_err().to_string();
+        assert_eq!(
+            error(r#"["Hi"]"#),
+            "JSON Parse Error: Job must be a JSON object with 'template' and 'context' fields."
+        );
+        assert_eq!(
+            error(r#"{"context": {}}"#),
+            "JSON Parse Error: Job is missing the 'template' field."
+        );
+        assert_eq!(
+            error(r#"{"template": 1}"#),
+            "JSON Parse Error: Job field 'template' must be a string."
+        );
+        assert!(parse_job(r#"{"template": "x", "context": [1]}"#).is_err());
+    }
+
+    #[test]
+    fn batch_stops_at_first_failure_by_default() {
+        let (job, contexts) = batch();
+        let engine = Engine::default();
+        let result = render_documents(
+            &job,
+            &engine,
+            &contexts,
+            Path::new("out"),
+            "{{ loop.index }}.txt",
+            false,
+        );
+        assert!(result.is_err());
+    }
+}
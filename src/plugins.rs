@@ -0,0 +1,488 @@
+//! Plugins: modules loaded at startup whose functions (and, for native
+//! plugins, filters) are callable from templates like the built-in ones.
+//!
+//! Both kinds exchange JSON. A function receives its arguments as a JSON
+//! object, a filter receives `{"value": ..., "args": [...]}`, and both answer
+//! with `{"ok": value}` or `{"error": value}`.
+//!
+//! WebAssembly plugins (`*.wasm`) export their linear `memory`, an
+//! `alloc(len: i32) -> i32` function returning a buffer of `len` bytes, and
+//! any number of template functions of type `(ptr: i32, len: i32) -> i64`.
+//! The input is written to `ptr..ptr + len`, the function returns the
+//! location of its result packed as `ptr << 32 | len`. They are sandboxed:
+//! they get no imports (so no file system, network or clock), their memory
+//! is capped and each call runs on a fuel budget.
+//!
+//! Native plugins (`libruntpl_*.so`, `libruntpl_*.dylib`, `runtpl_*.dll`)
+//! export `runtpl_register(const RuntplRegistrar *)`, which registers their
+//! functions and filters, and `runtpl_free(char *)`, which frees the results
+//! they return. They run with the full rights of runtpl.
+
+use crate::config;
+use crate::error::AppError;
+use crate::warnings;
+use libloading::Library;
+use serde_json::{Map, Value};
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use wasmi::{Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+const WASM_EXTENSION: &str = "wasm";
+
+/// Start of the file name of native plugins, after the platform's library prefix.
+const NATIVE_PLUGIN_PREFIX: &str = "runtpl_";
+
+/// Version of the `RuntplRegistrar` layout passed to native plugins.
+pub const NATIVE_ABI_VERSION: u32 = 1;
+
+/// Upper bound for the linear memory of a plugin.
+const MAX_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+/// Instructions a single call may execute before it is aborted, so a looping
+/// plugin can't hang the render.
+const FUEL_PER_CALL: u64 = 100_000_000;
+
+/// Largest result a WebAssembly plugin call may return.
+const MAX_RESULT_BYTES: usize = 16 * 1024 * 1024;
+
+/// A loaded plugin: named functions and filters callable from templates.
+pub trait Plugin: fmt::Debug + Send + Sync {
+    /// The file the plugin was loaded from.
+    fn path(&self) -> &Path;
+
+    /// Names of the template functions the plugin provides.
+    fn functions(&self) -> &[String];
+
+    /// Calls the function `name` with `args`, following the same contract
+    /// as built-in functions.
+    fn call(&self, name: &str, args: &Map<String, Value>) -> Result<Value, Value>;
+
+    /// Names of the filters the plugin provides.
+    fn filters(&self) -> &[String] {
+        &[]
+    }
+
+    /// Applies the filter `name` to `value`.
+    fn apply_filter(&self, name: &str, _value: &Value, _args: &[Value]) -> Result<Value, String> {
+        Err(format!("Plugin has no filter '{}'", name))
+    }
+}
+
+/// Reads a plugin's `{"ok": value}` or `{"error": value}` answer.
+fn parse_result(output: &[u8]) -> Result<Result<Value, Value>, String> {
+    match serde_json::from_slice(output) {
+        Ok(Value::Object(mut result)) => {
+            if let Some(error) = result.remove("error") {
+                Ok(Err(error))
+            } else if let Some(value) = result.remove("ok") {
+                Ok(Ok(value))
+            } else {
+                Err("Plugin result must have an 'ok' or 'error' field".to_string())
+            }
+        }
+        Ok(_) => Err("Plugin result must be a JSON object".to_string()),
+        Err(e) => Err(format!("Plugin returned invalid JSON: {}", e)),
+    }
+}
+
+struct PluginState {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    instance: Instance,
+}
+
+/// A loaded plugin module. Calls are serialized, since a module instance
+/// has a single linear memory.
+pub struct WasmPlugin {
+    path: PathBuf,
+    functions: Vec<String>,
+    state: Mutex<PluginState>,
+}
+
+impl fmt::Debug for WasmPlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmPlugin")
+            .field("path", &self.path)
+            .field("functions", &self.functions)
+            .finish()
+    }
+}
+
+/// Returns the directory plugins are loaded from, `plugins/` in the runtpl
+/// configuration directory.
+pub fn default_dir() -> Result<PathBuf, AppError> {
+    Ok(config::app_dir()?.join("plugins"))
+}
+
+fn is_native_plugin(file_name: &str) -> bool {
+    file_name
+        .strip_prefix(DLL_PREFIX)
+        .is_some_and(|rest| rest.starts_with(NATIVE_PLUGIN_PREFIX))
+        && file_name.ends_with(DLL_SUFFIX)
+}
+
+/// Loads every WebAssembly and native plugin of `dir` in name order.
+/// A missing directory means no plugins. A plugin that fails to load is
+/// skipped with a warning, so it can't break commands that don't need it.
+pub fn load_dir(dir: &Path) -> Result<Vec<Box<dyn Plugin>>, AppError> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file())
+        .collect();
+    paths.sort();
+
+    let mut plugins: Vec<Box<dyn Plugin>> = Vec::new();
+    for path in paths {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let loaded: Result<Box<dyn Plugin>, AppError> =
+            if path.extension().is_some_and(|ext| ext == WASM_EXTENSION) {
+                WasmPlugin::load(&path).map(|plugin| Box::new(plugin) as Box<dyn Plugin>)
+            } else if is_native_plugin(&file_name) {
+                NativePlugin::load(&path).map(|plugin| Box::new(plugin) as Box<dyn Plugin>)
+            } else {
+                continue;
+            };
+        match loaded {
+            Ok(plugin) => plugins.push(plugin),
+            Err(AppError::Plugin(msg)) => warnings::warn(format!("{}, skipping it", msg)),
+            Err(e) => warnings::warn(format!(
+                "Could not load '{}': {}, skipping it",
+                path.display(),
+                e
+            )),
+        }
+    }
+    Ok(plugins)
+}
+
+impl WasmPlugin {
+    pub fn load(path: &Path) -> Result<WasmPlugin, AppError> {
+        let plugin_err =
+            |msg: String| AppError::Plugin(format!("Could not load '{}': {}", path.display(), msg));
+
+        let wasm = fs::read(path)?;
+        let mut engine_config = wasmi::Config::default();
+        engine_config.consume_fuel(true);
+        let engine = wasmi::Engine::new(&engine_config);
+        let module = Module::new(&engine, &wasm).map_err(|e| plugin_err(e.to_string()))?;
+
+        if let Some(import) = module.imports().next() {
+            return Err(plugin_err(format!(
+                "plugins run sandboxed and cannot import '{}.{}'",
+                import.module(),
+                import.name()
+            )));
+        }
+
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(MAX_MEMORY_BYTES)
+            .build();
+        let mut store = Store::new(&engine, limits);
+        store.limiter(|limits| limits);
+        store
+            .set_fuel(FUEL_PER_CALL)
+            .map_err(|e| plugin_err(e.to_string()))?;
+
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| plugin_err(e.to_string()))?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| plugin_err("missing exported 'memory'".to_string()))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|_| plugin_err("missing exported 'alloc(i32) -> i32'".to_string()))?;
+
+        let mut functions: Vec<String> = instance
+            .exports(&store)
+            .filter(|export| export.name() != "alloc")
+            .filter_map(|export| {
+                let name = export.name().to_string();
+                let func = export.into_func()?;
+                func.typed::<(i32, i32), i64>(&store).ok().map(|_| name)
+            })
+            .collect();
+        functions.sort();
+
+        Ok(WasmPlugin {
+            path: path.to_path_buf(),
+            functions,
+            state: Mutex::new(PluginState {
+                store,
+                memory,
+                alloc,
+                instance,
+            }),
+        })
+    }
+
+    fn call_locked(
+        &self,
+        state: &mut PluginState,
+        name: &str,
+        args: &Map<String, Value>,
+    ) -> Result<Result<Value, Value>, String> {
+        let PluginState {
+            store,
+            memory,
+            alloc,
+            instance,
+        } = state;
+        let func = instance
+            .get_typed_func::<(i32, i32), i64>(&*store, name)
+            .map_err(|_| format!("Plugin does not export '{}'", name))?;
+        store.set_fuel(FUEL_PER_CALL).map_err(|e| e.to_string())?;
+
+        let input = Value::Object(args.clone()).to_string();
+        let input_len =
+            i32::try_from(input.len()).map_err(|_| "Arguments too large".to_string())?;
+        let input_ptr = alloc
+            .call(&mut *store, input_len)
+            .map_err(|e| format!("Plugin trapped in 'alloc': {}", e))?;
+        memory
+            .write(&mut *store, input_ptr as u32 as usize, input.as_bytes())
+            .map_err(|_| "Plugin 'alloc' returned an out-of-bounds buffer".to_string())?;
+
+        let packed = func
+            .call(&mut *store, (input_ptr, input_len))
+            .map_err(|e| format!("Plugin trapped: {}", e))? as u64;
+        let (output_ptr, output_len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        // Both halves come from the plugin: check them before trusting them
+        if output_len > MAX_RESULT_BYTES {
+            return Err(format!(
+                "Plugin result is larger than {} MiB",
+                MAX_RESULT_BYTES / (1024 * 1024)
+            ));
+        }
+        let data = memory.data(&*store);
+        let output = output_ptr
+            .checked_add(output_len)
+            .and_then(|end| data.get(output_ptr..end))
+            .ok_or_else(|| "Plugin returned an out-of-bounds result".to_string())?;
+
+        parse_result(output)
+    }
+}
+
+impl Plugin for WasmPlugin {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn functions(&self) -> &[String] {
+        &self.functions
+    }
+
+    fn call(&self, name: &str, args: &Map<String, Value>) -> Result<Value, Value> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.call_locked(&mut state, name, args)
+            .map_err(Value::String)?
+    }
+}
+
+/// Function or filter of a native plugin: takes a NUL-terminated JSON input
+/// and returns a JSON result allocated by the plugin, freed with `runtpl_free`.
+pub type NativeFn = unsafe extern "C" fn(input: *const c_char) -> *mut c_char;
+
+/// Passed to a native plugin's `runtpl_register`, which calls
+/// `register_function` and `register_filter` with `host` for each of its
+/// functions and filters.
+#[repr(C)]
+pub struct RuntplRegistrar {
+    pub abi_version: u32,
+    pub host: *mut c_void,
+    pub register_function:
+        unsafe extern "C" fn(host: *mut c_void, name: *const c_char, func: NativeFn),
+    pub register_filter:
+        unsafe extern "C" fn(host: *mut c_void, name: *const c_char, func: NativeFn),
+}
+
+#[derive(Default)]
+struct NativeRegistrations {
+    functions: Vec<(String, NativeFn)>,
+    filters: Vec<(String, NativeFn)>,
+}
+
+unsafe extern "C" fn register_native_function(
+    host: *mut c_void,
+    name: *const c_char,
+    func: NativeFn,
+) {
+    // SAFETY: `host` is the `NativeRegistrations` passed to `runtpl_register`,
+    // and `name` a NUL-terminated string, as the ABI requires.
+    let (registrations, name) = unsafe {
+        (
+            &mut *(host as *mut NativeRegistrations),
+            CStr::from_ptr(name),
+        )
+    };
+    registrations
+        .functions
+        .push((name.to_string_lossy().into_owned(), func));
+}
+
+unsafe extern "C" fn register_native_filter(
+    host: *mut c_void,
+    name: *const c_char,
+    func: NativeFn,
+) {
+    // SAFETY: as in `register_native_function`
+    let (registrations, name) = unsafe {
+        (
+            &mut *(host as *mut NativeRegistrations),
+            CStr::from_ptr(name),
+        )
+    };
+    registrations
+        .filters
+        .push((name.to_string_lossy().into_owned(), func));
+}
+
+/// A loaded native plugin. The library stays loaded as long as the plugin
+/// exists, which keeps its function pointers valid.
+pub struct NativePlugin {
+    path: PathBuf,
+    function_names: Vec<String>,
+    filter_names: Vec<String>,
+    functions: Vec<(String, NativeFn)>,
+    filters: Vec<(String, NativeFn)>,
+    free: unsafe extern "C" fn(*mut c_char),
+    _library: Library,
+}
+
+impl fmt::Debug for NativePlugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NativePlugin")
+            .field("path", &self.path)
+            .field("functions", &self.function_names)
+            .field("filters", &self.filter_names)
+            .finish()
+    }
+}
+
+impl NativePlugin {
+    pub fn load(path: &Path) -> Result<NativePlugin, AppError> {
+        let plugin_err =
+            |msg: String| AppError::Plugin(format!("Could not load '{}': {}", path.display(), msg));
+
+        // SAFETY: loading a library runs its initializers; native plugins are
+        // trusted code by design, which the documentation points out.
+        let library = unsafe { Library::new(path) }.map_err(|e| plugin_err(e.to_string()))?;
+        // SAFETY: the symbols are declared with the signatures the ABI defines.
+        let abi_version = unsafe {
+            *library
+                .get::<unsafe extern "C" fn() -> u32>(b"runtpl_abi_version\0")
+                .map_err(|e| plugin_err(e.to_string()))?
+        };
+        // SAFETY: as above; nothing else of the plugin runs before this check.
+        let version = unsafe { abi_version() };
+        if version != NATIVE_ABI_VERSION {
+            return Err(plugin_err(format!(
+                "built for plugin ABI version {}, runtpl supports version {}",
+                version, NATIVE_ABI_VERSION
+            )));
+        }
+
+        // SAFETY: the symbols are declared with the signatures the ABI defines.
+        let (register, free) = unsafe {
+            let register = *library
+                .get::<unsafe extern "C" fn(*const RuntplRegistrar)>(b"runtpl_register\0")
+                .map_err(|e| plugin_err(e.to_string()))?;
+            let free = *library
+                .get::<unsafe extern "C" fn(*mut c_char)>(b"runtpl_free\0")
+                .map_err(|e| plugin_err(e.to_string()))?;
+            (register, free)
+        };
+
+        let mut registrations = NativeRegistrations::default();
+        let registrar = RuntplRegistrar {
+            abi_version: NATIVE_ABI_VERSION,
+            host: &mut registrations as *mut NativeRegistrations as *mut c_void,
+            register_function: register_native_function,
+            register_filter: register_native_filter,
+        };
+        // SAFETY: `registrar` and the registrations it points to outlive the call.
+        unsafe { register(&registrar) };
+
+        let names = |entries: &[(String, NativeFn)]| {
+            let mut names: Vec<String> = entries.iter().map(|(name, _)| name.clone()).collect();
+            names.sort();
+            names
+        };
+        Ok(NativePlugin {
+            path: path.to_path_buf(),
+            function_names: names(&registrations.functions),
+            filter_names: names(&registrations.filters),
+            functions: registrations.functions,
+            filters: registrations.filters,
+            free,
+            _library: library,
+        })
+    }
+
+    fn invoke(&self, func: NativeFn, input: &Value) -> Result<Result<Value, Value>, String> {
+        let input = CString::new(input.to_string())
+            .map_err(|_| "Plugin input contains a NUL byte".to_string())?;
+        // SAFETY: `func` comes from this plugin's library, which is still loaded,
+        // and gets a NUL-terminated string valid for the duration of the call.
+        let output = unsafe { func(input.as_ptr()) };
+        if output.is_null() {
+            return Err("Plugin returned no result".to_string());
+        }
+        // SAFETY: a non-null result is a NUL-terminated string owned by the
+        // plugin, read once and handed back to `runtpl_free`.
+        unsafe {
+            let result = parse_result(CStr::from_ptr(output).to_bytes());
+            (self.free)(output);
+            result
+        }
+    }
+}
+
+impl Plugin for NativePlugin {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn functions(&self) -> &[String] {
+        &self.function_names
+    }
+
+    fn call(&self, name: &str, args: &Map<String, Value>) -> Result<Value, Value> {
+        let (_, func) = self
+            .functions
+            .iter()
+            .find(|(func_name, _)| func_name == name)
+            .ok_or_else(|| Value::String(format!("Plugin does not provide '{}'", name)))?;
+        self.invoke(*func, &Value::Object(args.clone()))
+            .map_err(Value::String)?
+    }
+
+    fn filters(&self) -> &[String] {
+        &self.filter_names
+    }
+
+    fn apply_filter(&self, name: &str, value: &Value, args: &[Value]) -> Result<Value, String> {
+        let (_, func) = self
+            .filters
+            .iter()
+            .find(|(filter_name, _)| filter_name == name)
+            .ok_or_else(|| format!("Plugin has no filter '{}'", name))?;
+        let input = serde_json::json!({ "value": value, "args": args });
+        self.invoke(*func, &input)?.map_err(|e| match e {
+            Value::String(msg) => msg,
+            other => other.to_string(),
+        })
+    }
+}
@@ -0,0 +1,52 @@
+//! `{{script}}` blocks, evaluated with the embedded Rhai engine.
+
+use crate::warnings;
+use rhai::{Dynamic, Scope};
+use serde_json::Value;
+
+/// Operations a script may perform before it is aborted, so a runaway loop
+/// can't hang the render.
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+/// Evaluates `source` and returns the value of its last expression (`null`
+/// for statements). The top-level fields of `context` are visible to the
+/// script as constants; fields whose names aren't valid Rhai identifiers
+/// are skipped.
+pub(crate) fn evaluate(source: &str, context: &Value) -> Result<Value, String> {
+    let mut engine = rhai::Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.on_print(|text| warnings::warn(format!("script: {}", text)));
+    engine.on_debug(|text, _, pos| warnings::warn(format!("script ({}): {}", pos, text)));
+
+    let mut scope = Scope::new();
+    if let Some(fields) = context.as_object() {
+        for (name, value) in fields {
+            if !is_identifier(name) {
+                continue;
+            }
+            let value = rhai::serde::to_dynamic(value).map_err(|e| e.to_string())?;
+            scope.push_constant_dynamic(name.clone(), value);
+        }
+    }
+
+    let result = engine
+        .eval_with_scope::<Dynamic>(&mut scope, source)
+        .map_err(|e| format!("Script error: {}", e))?;
+    if result.is_unit() {
+        return Ok(Value::Null);
+    }
+    rhai::serde::from_dynamic(&result).map_err(|e| {
+        format!(
+            "Script returned a value that can't be used in a template: {}",
+            e
+        )
+    })
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
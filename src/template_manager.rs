@@ -1,18 +1,20 @@
+use crate::config;
+use crate::engine::Engine;
 use crate::error::AppError;
+use std::collections::BTreeSet;
 use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 
 const TEMPLATE_EXTENSION: &str = "tpl";
 
+/// Suffix of the file holding a template's helper macros: `name.helpers.tpl`.
+const HELPERS_SUFFIX: &str = ".helpers";
+
 /// Returns the path to the central template storage directory.
 /// Creates the directory if it doesn't exist.
 fn get_template_dir() -> Result<PathBuf, AppError> {
-    let config_dir = dirs::config_dir().ok_or_else(|| {
-        AppError::Editor("Could not find a valid configuration directory.".to_string())
-    })?;
-    let app_dir = config_dir.join("runtpl");
-    let templates_dir = app_dir.join("templates");
+    let templates_dir = config::app_dir()?.join("templates");
 
     if !templates_dir.exists() {
         fs::create_dir_all(&templates_dir)?;
@@ -48,6 +50,22 @@ pub fn resolve_template_path(name: &str) -> Result<PathBuf, AppError> {
     )))
 }
 
+/// Returns the helpers file accompanying a template, `mytemplate.helpers.tpl`
+/// next to `mytemplate.tpl`, if there is one.
+pub fn resolve_helpers_path(template_path: &Path) -> Option<PathBuf> {
+    helpers_path(template_path).filter(|path| path.is_file())
+}
+
+/// Where the helpers file of a template goes, whether it exists or not.
+/// `None` for a helpers file itself, which has no helpers of its own.
+pub fn helpers_path(template_path: &Path) -> Option<PathBuf> {
+    let stem = template_path.file_stem()?.to_str()?;
+    if stem.ends_with(HELPERS_SUFFIX) {
+        return None;
+    }
+    Some(template_path.with_file_name(format!("{}{}.{}", stem, HELPERS_SUFFIX, TEMPLATE_EXTENSION)))
+}
+
 /// Handles the `template list` command.
 pub fn list_templates() -> Result<(), AppError> {
     let dir = get_template_dir()?;
@@ -60,20 +78,40 @@ pub fn list_templates() -> Result<(), AppError> {
         return Ok(());
     }
 
-    for entry in entries {
+    for name in template_names()? {
+        println!("- {}", name);
+    }
+    Ok(())
+}
+
+/// Names of the templates in the central store, without helpers files.
+pub fn template_names() -> Result<Vec<String>, AppError> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(get_template_dir()?)? {
         let path = entry?.path();
-        if path.is_file() {
-            if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
-                println!("- {}", stem);
-            }
+        if path.is_file()
+            && let Some(stem) = path.file_stem().and_then(|s| s.to_str())
+            && !stem.ends_with(HELPERS_SUFFIX)
+        {
+            names.push(stem.to_string());
         }
     }
-    Ok(())
+    Ok(names)
+}
+
+/// Opens a template file in the user's editor.
+type Editor = fn(&Path) -> Result<(), AppError>;
+
+fn open_in_editor(path: &Path) -> Result<(), AppError> {
+    edit::edit_file(path).map_err(|e| AppError::Editor(e.to_string()))
 }
 
 /// Handles the `template new <name>` command.
 pub fn new_template(name: &str) -> Result<(), AppError> {
-    let path = get_template_path(name)?;
+    create_template_at(name, &get_template_path(name)?, open_in_editor)
+}
+
+fn create_template_at(name: &str, path: &Path, editor: Editor) -> Result<(), AppError> {
     if path.exists() {
         return Err(AppError::InvalidArgument(format!(
             "Template '{}' already exists. Use 'runtpl template edit {}' to edit it.",
@@ -81,13 +119,13 @@ pub fn new_template(name: &str) -> Result<(), AppError> {
         )));
     }
 
-    fs::File::create(&path)?;
+    fs::File::create(path)?;
 
     println!("Opening editor for new template: {}", path.display());
-    edit::edit_file(&path).map_err(|e| AppError::Editor(e.to_string()))?;
+    editor(path)?;
 
-    if fs::metadata(&path)?.len() == 0 {
-        fs::remove_file(&path)?;
+    if fs::metadata(path)?.len() == 0 {
+        fs::remove_file(path)?;
         println!("Empty template discarded. Creation cancelled.");
     } else {
         println!("Template '{}' created successfully.", name);
@@ -97,21 +135,60 @@ pub fn new_template(name: &str) -> Result<(), AppError> {
 }
 
 /// Handles the `template edit <name>` command.
-pub fn edit_template(name: &str) -> Result<(), AppError> {
-    let path = get_template_path(name)?;
+/// With `create`, a missing template is created as if by `template new`.
+pub fn edit_template(name: &str, create: bool) -> Result<(), AppError> {
+    edit_template_at(name, &get_template_path(name)?, create, open_in_editor)
+}
+
+fn edit_template_at(name: &str, path: &Path, create: bool, editor: Editor) -> Result<(), AppError> {
     if !path.exists() {
+        if create {
+            println!("Template '{}' does not exist yet, creating it.", name);
+            return create_template_at(name, path, editor);
+        }
         return Err(AppError::InvalidArgument(format!(
-            "Template '{}' not found. Use 'runtpl template new {}' to create it.",
-            name, name
+            "Template '{}' not found. Use 'runtpl template new {}' or 'runtpl template edit --create {}' to create it.",
+            name, name, name
         )));
     }
 
+    let before = fs::read_to_string(path)?;
+
     println!("Opening editor for template: {}", path.display());
-    edit::edit_file(&path).map_err(|e| AppError::Editor(e.to_string()))?;
+    editor(path)?;
+
     println!("Template '{}' saved.", name);
+
+    let after = fs::read_to_string(path)?;
+    if before != after {
+        for change in variable_changes(&before, &after) {
+            println!("  {}", change);
+        }
+    }
     Ok(())
 }
 
+/// Lists the variables added (`+ name`) or removed (`- name`) by an edit, so
+/// accidental renames that break existing data files are easy to spot.
+fn variable_changes(before: &str, after: &str) -> Vec<String> {
+    let engine = Engine::default();
+    let old_vars = engine.extract_variables(before);
+    let new_vars = engine.extract_variables(after);
+
+    let added: BTreeSet<_> = new_vars
+        .keys()
+        .filter(|var| !old_vars.contains_key(*var))
+        .collect();
+    let removed: BTreeSet<_> = old_vars
+        .keys()
+        .filter(|var| !new_vars.contains_key(*var))
+        .collect();
+
+    let added = added.into_iter().map(|var| format!("+ {}", var));
+    let removed = removed.into_iter().map(|var| format!("- {}", var));
+    added.chain(removed).collect()
+}
+
 /// Handles the `template remove <name>` command.
 pub fn remove_template(name: &str) -> Result<(), AppError> {
     let path = get_template_path(name)?;
@@ -142,3 +219,50 @@ pub fn remove_template(name: &str) -> Result<(), AppError> {
     println!("Template '{}' removed successfully.", name);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edit_with_create_creates_a_missing_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("greeting.tpl");
+        edit_template_at("greeting", &path, true, |path| {
+            fs::write(path, "Hello {{ name }}").map_err(AppError::from)
+        })
+        .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "Hello {{ name }}");
+    }
+
+    #[test]
+    fn created_template_left_empty_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("greeting.tpl");
+        edit_template_at("greeting", &path, true, |_| Ok(())).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn edit_without_create_needs_an_existing_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("greeting.tpl");
+        let result = edit_template_at("greeting", &path, false, |_| Ok(()));
+        assert!(matches!(result, Err(AppError::InvalidArgument(_))));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn edit_adding_a_variable_is_reported() {
+        let changes = variable_changes("Hi {{ name }}", "Hi {{ name }} from {{ city }}");
+        assert_eq!(changes, ["+ city"]);
+        let changes = variable_changes("{{ old }}", "{{ new }}");
+        assert_eq!(changes, ["+ new", "- old"]);
+    }
+
+    #[test]
+    fn unchanged_variables_report_nothing() {
+        assert!(variable_changes("Hi {{ name }}", "Hi {{ name }}").is_empty());
+        assert!(variable_changes("Hi {{ name }}", "Hello, {{ name }}!").is_empty());
+    }
+}
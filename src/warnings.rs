@@ -0,0 +1,24 @@
+use std::cell::RefCell;
+
+thread_local! {
+    static CAPTURED: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Reports a non-fatal problem. Printed to stderr, unless warnings are
+/// currently being captured (see `start_capture`).
+pub fn warn(msg: String) {
+    CAPTURED.with(|captured| match captured.borrow_mut().as_mut() {
+        Some(list) => list.push(msg),
+        None => eprintln!("Warning: {}", msg),
+    });
+}
+
+/// Starts collecting warnings instead of printing them.
+pub fn start_capture() {
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+}
+
+/// Stops capturing and returns everything collected so far.
+pub fn take_captured() -> Vec<String> {
+    CAPTURED.with(|captured| captured.borrow_mut().take().unwrap_or_default())
+}
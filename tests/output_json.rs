@@ -0,0 +1,54 @@
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Runs `runtpl run <template> --output-json` with a config directory of its own.
+fn run_json(dir: &Path, template: &str, args: &[&str]) -> Output {
+    let template_path = dir.join("test.tpl");
+    fs::write(&template_path, template).unwrap();
+    Command::new(env!("CARGO_BIN_EXE_runtpl"))
+        .arg("run")
+        .arg(&template_path)
+        .args(args)
+        .args(["--output-json", "--no-copy"])
+        .env("XDG_CONFIG_HOME", dir.join("config"))
+        .env("HOME", dir)
+        .output()
+        .unwrap()
+}
+
+fn envelope(output: &Output) -> Value {
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn success_envelope_holds_the_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_json(dir.path(), "Hi {{ name }}", &["name=Ann"]);
+
+    assert!(output.status.success());
+    let envelope = envelope(&output);
+    assert_eq!(envelope["ok"], true);
+    assert_eq!(envelope["output"], "Hi Ann");
+    assert_eq!(envelope["warnings"], Value::Array(vec![]));
+}
+
+#[test]
+fn error_envelope_exits_nonzero() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = run_json(dir.path(), "{{ 1 / n }}", &["n=0"]);
+
+    assert_eq!(output.status.code(), Some(1));
+    let envelope = envelope(&output);
+    assert_eq!(envelope["ok"], false);
+    assert!(envelope.get("output").is_none());
+    assert!(
+        envelope["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Error rendering template:"),
+        "{}",
+        envelope["error"]
+    );
+}